@@ -0,0 +1,111 @@
+#!/usr/bin/env perl
+# src/engine/unicode_tables.rsを生成する
+# PerlにバンドルされているUnicodeのデータベースを使うので、ネットワークは不要
+#
+# 使い方: perl scripts/gen_unicode_tables.pl > src/engine/unicode_tables.rs
+use strict;
+use warnings;
+use Unicode::UCD qw(prop_invlist prop_invmap prop_values prop_value_aliases);
+
+# 反転リスト(範囲の開始位置と終了位置+1が交互に並ぶ)を(start, end)の列に変換する
+# サロゲート領域はcharで表せないので取り除く
+sub ranges {
+    my @list = prop_invlist(shift);
+    push @list, 0x110000 if @list % 2;
+
+    my @ranges;
+    while (my ($start, $end) = splice(@list, 0, 2)) {
+        $end -= 1;
+        if ($start <= 0xDFFF && $end >= 0xD800) {
+            push @ranges, [$start, 0xD7FF] if $start < 0xD800;
+            push @ranges, [0xE000, $end] if $end > 0xDFFF;
+        } else {
+            push @ranges, [$start, $end];
+        }
+    }
+    return @ranges;
+}
+
+sub print_table {
+    my ($name, $doc, $prop) = @_;
+    print "/// $doc\n";
+    print "pub const $name: &[(char, char)] = &[\n";
+    my @ranges = ranges($prop);
+    while (my @line = splice(@ranges, 0, 4)) {
+        print "    ", join(" ", map { sprintf("('\\u{%X}', '\\u{%X}'),", @$_) } @line), "\n";
+    }
+    print "];\n";
+}
+
+# 単純な大文字小文字の畳み込み(CaseFolding.txtのCとS)を(元の文字, 畳み込んだ文字)の列として出力する
+# 畳み込んでも変わらない文字は含めない
+sub print_folding {
+    my ($name, $doc) = @_;
+    my ($list, $map, $format, $default) = prop_invmap("Simple_Case_Folding");
+    die "unexpected format: $format" unless $format eq "a";
+
+    my @pairs;
+    for my $i (0 .. $#$list - 1) {
+        next if $map->[$i] eq $default;
+        for my $c ($list->[$i] .. $list->[$i + 1] - 1) {
+            push @pairs, [$c, $map->[$i] + $c - $list->[$i]];
+        }
+    }
+
+    print "/// $doc\n";
+    print "pub const $name: &[(char, char)] = &[\n";
+    while (my @line = splice(@pairs, 0, 4)) {
+        print "    ", join(" ", map { sprintf("('\\u{%X}', '\\u{%X}'),", @$_) } @line), "\n";
+    }
+    print "];\n";
+}
+
+# \p{...}で名前を比較するための正規化。小文字にして空白、_、-を取り除く
+sub normalize {
+    my $name = lc shift;
+    $name =~ s/[\s_-]//g;
+    return $name;
+}
+
+# 性質の値ごとに表を出力し、続けて別名を含む正規化した名前から表を引くための一覧を出力する
+sub print_property {
+    my ($name, $doc, $prop, $prefix) = @_;
+    my @names;
+    for my $value (sort(prop_values($prop))) {
+        my @aliases = prop_value_aliases($prop, $value);
+        # 別名のない値(文字を1つも持たないKatakana_Or_Hiraganaなど)は使えないので飛ばす
+        next unless @aliases;
+        my $table = $prefix . "_" . uc($aliases[1]);
+        print_table($table, "$prop=" . join("|", @aliases), "$prop=$value");
+        print "\n";
+        my %seen;
+        push @names, map { [normalize($_), $table] } grep { !$seen{normalize($_)}++ } @aliases;
+    }
+
+    print "/// $doc\n";
+    print "pub const $name: &[(&str, &[(char, char)])] = &[\n";
+    for my $entry (sort { $a->[0] cmp $b->[0] } @names) {
+        print "    (\"$entry->[0]\", $entry->[1]),\n";
+    }
+    print "];\n";
+}
+
+print "//! Unicodeの性質ごとの文字の範囲の表と、大文字小文字の畳み込みの表\n";
+print "//! scripts/gen_unicode_tables.plで生成したファイルなので、直接編集しないこと\n";
+print "//!\n";
+print "//! Unicode ", Unicode::UCD::UnicodeVersion(), "\n";
+print "\n";
+
+print_table("PERL_DIGIT", "\\dのUnicode版。General_Category=Decimal_Number", "gc=Nd");
+print "\n";
+print_table("PERL_SPACE", "\\sのUnicode版。White_Space", "White_Space");
+print "\n";
+print_table("PERL_WORD", "\\wのUnicode版。Alphabetic、Mark、Decimal_Number、Connector_Punctuation、Join_Control", "Word");
+print "\n";
+print_folding("SIMPLE_CASE_FOLDING", "単純な大文字小文字の畳み込み。元の文字でソート済み");
+print "\n";
+print_property("GENERAL_CATEGORY", "\\p{...}で使う一般カテゴリの正規化した名前と表。名前でソート済み", "gc", "GC");
+print "\n";
+print_property("SCRIPT", "\\p{sc=...}で使う用字の正規化した名前と表。名前でソート済み", "sc", "SC");
+print "\n";
+print_property("SCRIPT_EXTENSIONS", "\\p{...}や\\p{scx=...}で使う用字拡張の正規化した名前と表。名前でソート済み", "scx", "SCX");
@@ -1,31 +1,428 @@
+mod class;
 mod codegen;
 mod evaluator;
 mod parser;
+#[rustfmt::skip]
+mod unicode_tables;
 
 use crate::helper::DynError;
-use std::fmt::{self, Display};
+use evaluator::Slots;
+use std::{
+    fmt::{self, Display},
+    sync::Arc,
+};
+
+/// 文字を消費せず、現在の位置が条件を満たすかだけを調べる命令の条件
+/// unicodeが偽なら、単語を構成する文字をASCIIの\wの範囲のみとする
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Assertion {
+    StartText,                         // \A 文字列の先頭
+    EndText,                           // \z 文字列の末尾
+    StartLine,                         // (?m)での^ 文字列か行の先頭
+    EndLine,                           // (?m)での$ 文字列か行の末尾
+    WordBoundary { unicode: bool },    // \b 単語の境界
+    NotWordBoundary { unicode: bool }, // \B 単語の境界以外
+}
+
+impl Display for Assertion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Assertion::StartText => write!(f, "start of text"),
+            Assertion::EndText => write!(f, "end of text"),
+            Assertion::StartLine => write!(f, "start of line"),
+            Assertion::EndLine => write!(f, "end of line"),
+            Assertion::WordBoundary { .. } => write!(f, "word boundary"),
+            Assertion::NotWordBoundary { .. } => write!(f, "not word boundary"),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub enum Instruction {
     Char(char),
-    Dot,
+    CharFold(char), // 入力を畳み込んだ文字がこの文字と等しければマッチ
+    Dot,            // 改行以外の任意の1文字にマッチ
+    AnyChar,        // 改行も含む任意の1文字にマッチ
     Match,
     Jump(usize),
     Split(usize, usize),
+    Class(Vec<(char, char)>), // 正規化済みの範囲のどれかに含まれる文字にマッチ
+    Save(usize),              // 現在の位置をキャプチャのスロットに保存する
+    Assert(Assertion),        // 現在の位置が条件を満たさなければ失敗する
+    // スロットに保存した位置から進んでいなければ2つ目のusizeの位置(ループの出口)へジャンプする
+    Progress(usize, usize),
+    // 次の命令からMatchまでの部分プログラムが現在の位置からマッチするか調べ、nextへ進む
+    // negatedが真ならマッチしない場合に進む。文字は消費しない
+    LookAhead {
+        negated: bool,
+        next: usize,
+    },
+    // 次の命令からMatchまでの逆順の部分プログラムが、現在の位置で終わる文字列にマッチするか調べる
+    // それ以外はLookAheadと同じ
+    LookBehind {
+        negated: bool,
+        next: usize,
+    },
+    Backref(usize), // グループがキャプチャした文字列にマッチ。キャプチャしていなければ失敗する
+    BackrefFold(usize), // Backrefと同じだが、畳み込んだ文字同士で比較する
+    // 次の命令からMatchまでの部分プログラムを評価し、最初に見つかったマッチの末尾からnextへ進む
+    // 部分プログラムの中の他の分岐は試さない
+    Atomic {
+        next: usize,
+    },
+    // グループがキャプチャしていれば次の命令へ、そうでなければ2つ目のusizeの位置へジャンプする
+    CondJump(usize, usize),
+    // groupのグループ(0ならパターン全体)を呼び出す。startからendの手前までを評価し、
+    // 最初に見つかったマッチの末尾から次の命令へ進む。呼び出しの中のキャプチャは残さない
+    Call {
+        group: usize,
+        start: usize,
+        end: usize,
+    },
 }
 
 impl Display for Instruction {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Instruction::Char(c) => write!(f, "char {}", c),
-            Instruction::Dot => write!(f, "any character is ok"),
+            Instruction::CharFold(c) => write!(f, "char fold {}", c),
+            Instruction::Dot => write!(f, "any character except newline is ok"),
+            Instruction::AnyChar => write!(f, "any character is ok"),
             Instruction::Match => write!(f, "match"),
             Instruction::Jump(addr) => write!(f, "jump {:>04}", addr),
             Instruction::Split(addr1, addr2) => write!(f, "split {:>04}, {:>04}", addr1, addr2),
+            Instruction::Save(slot) => write!(f, "save {}", slot),
+            Instruction::Assert(assertion) => write!(f, "assert {}", assertion),
+            Instruction::Progress(slot, addr) => write!(f, "progress {}, {:>04}", slot, addr),
+            Instruction::LookAhead { negated, next } => {
+                let kind = if *negated { "negative" } else { "positive" };
+                write!(f, "lookahead {kind}, next {:>04}", next)
+            }
+            Instruction::LookBehind { negated, next } => {
+                let kind = if *negated { "negative" } else { "positive" };
+                write!(f, "lookbehind {kind}, next {:>04}", next)
+            }
+            Instruction::Atomic { next } => write!(f, "atomic, next {:>04}", next),
+            Instruction::Call { group, start, end } => {
+                write!(f, "call {}, {:>04}-{:>04}", group, start, end)
+            }
+            Instruction::CondJump(group, addr) => {
+                write!(f, "condjump {}, {:>04}", group, addr)
+            }
+            Instruction::Backref(group) => write!(f, "backref {}", group),
+            Instruction::BackrefFold(group) => write!(f, "backref fold {}", group),
+            Instruction::Class(ranges) => {
+                write!(f, "class [")?;
+                for (start, end) in ranges {
+                    if start == end {
+                        write!(f, "{}", start.escape_debug())?;
+                    } else {
+                        write!(f, "{}-{}", start.escape_debug(), end.escape_debug())?;
+                    }
+                }
+                write!(f, "]")
+            }
+        }
+    }
+}
+
+/// マッチした範囲
+/// start, endは元の文字列におけるバイト位置で、endの位置の文字は含まない
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Match<'h> {
+    haystack: &'h str,
+    start: usize,
+    end: usize,
+}
+
+impl<'h> Match<'h> {
+    fn new(haystack: &'h str, start: usize, end: usize) -> Self {
+        Match {
+            haystack,
+            start,
+            end,
+        }
+    }
+
+    /// マッチの先頭のバイト位置
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    /// マッチの末尾のバイト位置
+    pub fn end(&self) -> usize {
+        self.end
+    }
+
+    /// マッチした文字列
+    pub fn as_str(&self) -> &'h str {
+        &self.haystack[self.start..self.end]
+    }
+}
+
+/// キャプチャグループごとのマッチした範囲
+/// グループ0はマッチ全体で、グループnは左からn番目の(に対応する
+#[derive(Debug, Clone)]
+pub struct Captures<'h> {
+    haystack: &'h str,
+    slots: Vec<Option<usize>>, // バイト位置。グループnの開始位置が2n、終了位置が2n+1
+    names: Arc<Vec<Option<String>>>, // グループ番号ごとの名前
+}
+
+impl<'h> Captures<'h> {
+    /// グループiがマッチした範囲を返す
+    /// グループiが存在しないか、マッチに参加しなかった場合はNoneを返す
+    pub fn get(&self, i: usize) -> Option<Match<'h>> {
+        let start = (*self.slots.get(i * 2)?)?;
+        let end = (*self.slots.get(i * 2 + 1)?)?;
+        Some(Match::new(self.haystack, start, end))
+    }
+
+    /// nameという名前のグループがマッチした範囲を返す
+    /// その名前のグループが存在しないか、マッチに参加しなかった場合はNoneを返す
+    pub fn name(&self, name: &str) -> Option<Match<'h>> {
+        let i = self.names.iter().position(|n| n.as_deref() == Some(name))?;
+        self.get(i)
+    }
+}
+
+/// 生成する命令数の上限のデフォルト値
+pub const DEFAULT_SIZE_LIMIT: usize = 100_000;
+
+/// 部分プログラムの入れ子の深さの上限のデフォルト値
+/// 入れ子1段ごとに評価器の関数呼び出しが1段深くなるので、最適化なしのビルドでも
+/// 2MBのスタックに十分な余裕を持って収まる値にしている
+pub const DEFAULT_RECURSION_LIMIT: usize = 100;
+
+/// 深さ優先探索で訪れた状態を記録するビット集合の大きさ(バイト)の上限のデフォルト値
+pub const DEFAULT_MEMO_BUDGET: usize = 256 * 1024;
+
+/// 深さ優先探索で後戻りのためにスタックに積む情報の数の上限のデフォルト値
+pub const DEFAULT_STACK_LIMIT: usize = 1_000_000;
+
+/// Regexのコンパイルと評価の設定
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// 有効の時深さ優先探索、無効の時幅優先探索で評価する
+    pub is_depth: bool,
+    /// 生成する命令数の上限。{n,m}の展開などで超えた場合はコンパイルエラーになる
+    pub size_limit: usize,
+    /// 有効の時\d \w \sをUnicodeの定義で、無効の時ASCIIの範囲のみで解釈する
+    pub unicode: bool,
+    /// 有効の時パターン全体で大文字小文字を区別しない。(?i)を先頭に書くのと同じ
+    pub case_insensitive: bool,
+    /// (?R)や(?1)の呼び出し、lookaround、アトミックグループの入れ子の深さの上限。超えた場合はマッチング時にエラーになる
+    /// 評価はネイティブのスタック上で再帰するので、大きくする場合はスタックの大きさに注意する
+    pub recursion_limit: usize,
+    /// 深さ優先探索で訪れた(pc, sp)を記録するビット集合の大きさ(バイト)の上限。ビット集合は探索1回につき1つ作る
+    /// 超える場合は幅優先探索で評価する
+    pub memo_budget: usize,
+    /// 深さ優先探索で後戻りのためにスタックに積む情報の数の上限。超えた場合はマッチング時にエラーになる
+    pub stack_limit: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            is_depth: true,
+            size_limit: DEFAULT_SIZE_LIMIT,
+            unicode: true,
+            case_insensitive: false,
+            recursion_limit: DEFAULT_RECURSION_LIMIT,
+            memo_budget: DEFAULT_MEMO_BUDGET,
+            stack_limit: DEFAULT_STACK_LIMIT,
+        }
+    }
+}
+
+/// コンパイル済みの正規表現
+/// パースとコード生成は生成時に一度だけ行い、以降は同じ命令列を使い回してマッチングする
+///
+/// # 利用例
+///
+/// ```text
+/// let re = lt_regex::Regex::new("abc|(de|cd)+")?;
+/// assert!(re.is_match("decddede")?);
+/// ```
+#[derive(Debug)]
+pub struct Regex {
+    code: Vec<Instruction>,
+    n_slots: usize, // スロット数。マッチ全体とキャプチャの分に加え、空ループの検出用の分も含む
+    names: Arc<Vec<Option<String>>>, // グループ番号ごとの名前。Capturesと共有する
+    anchored: bool, // 先頭が\Aや^で、文字列の先頭からしかマッチしない
+    is_depth: bool,
+    limits: evaluator::Limits, // 深さ優先探索で使う資源の上限
+}
+
+impl Regex {
+    /// 正規表現をコンパイルする。評価には深さ優先探索を用いる
+    pub fn new(expr: &str) -> Result<Regex, DynError> {
+        Regex::with_mode(expr, true)
+    }
+
+    /// 正規表現をコンパイルする
+    /// is_depthが有効の時深さ優先探索、無効の時幅優先探索で評価する
+    pub fn with_mode(expr: &str, is_depth: bool) -> Result<Regex, DynError> {
+        let config = Config {
+            is_depth,
+            ..Default::default()
+        };
+        Regex::with_config(expr, &config)
+    }
+
+    /// 設定を指定して正規表現をコンパイルする
+    pub fn with_config(expr: &str, config: &Config) -> Result<Regex, DynError> {
+        let ast_state = parser::parse(expr, config.unicode, config.case_insensitive)?;
+        let capture_slots = (ast_state.captures + 1) * 2;
+        let (code, n_slots) = codegen::get_code(&ast_state.ast, capture_slots, config.size_limit)?;
+        // 幅優先探索で評価できない命令があれば、マッチングを始める前にエラーにする
+        if !config.is_depth {
+            evaluator::check_width(&code)?;
+        }
+        let anchored = matches!(
+            code.first(),
+            Some(Instruction::Assert(Assertion::StartText))
+        );
+        Ok(Regex {
+            code,
+            n_slots,
+            names: Arc::new(ast_state.names),
+            anchored,
+            is_depth: config.is_depth,
+            limits: evaluator::Limits {
+                recursion: config.recursion_limit,
+                memo_budget: config.memo_budget,
+                stack: config.stack_limit,
+            },
+        })
+    }
+
+    /// haystackのどこかにマッチするならtrueを返す
+    pub fn is_match(&self, haystack: &str) -> Result<bool, DynError> {
+        Ok(self.find(haystack)?.is_some())
+    }
+
+    /// haystackの中で最も左にあるマッチを返す
+    pub fn find<'h>(&self, haystack: &'h str) -> Result<Option<Match<'h>>, DynError> {
+        self.find_iter(haystack).next().transpose()
+    }
+
+    /// haystackの中で最も左にあるマッチについて、キャプチャグループごとの範囲を返す
+    pub fn captures<'h>(&self, haystack: &'h str) -> Result<Option<Captures<'h>>, DynError> {
+        Matches::new(self, haystack).next_captures().transpose()
+    }
+
+    /// キャプチャグループの数を返す。マッチ全体を表すグループ0も含む
+    pub fn captures_len(&self) -> usize {
+        self.names.len()
+    }
+
+    /// グループ番号順にグループの名前を返すイテレータを返す
+    /// 名前のないグループとマッチ全体を表すグループ0はNoneになる
+    pub fn capture_names(&self) -> impl Iterator<Item = Option<&str>> {
+        self.names.iter().map(|n| n.as_deref())
+    }
+
+    /// haystackの中の重ならないマッチを左から順に返すイテレータを返す
+    pub fn find_iter<'r, 'h>(&'r self, haystack: &'h str) -> Matches<'r, 'h> {
+        Matches::new(self, haystack)
+    }
+
+    /// chars[start..]の中で最も左にあるマッチを探し、文字単位の位置でキャプチャのスロットを返す
+    fn find_at(&self, chars: &[char], start: usize) -> Result<Option<Slots>, DynError> {
+        let slots = evaluator::eval(
+            &self.code,
+            chars,
+            start,
+            self.n_slots,
+            self.is_depth,
+            self.anchored,
+            self.limits,
+        )?;
+        Ok(slots)
+    }
+}
+
+/// Regex::find_iterが返すイテレータ
+#[derive(Debug)]
+pub struct Matches<'r, 'h> {
+    regex: &'r Regex,
+    haystack: &'h str,
+    chars: Vec<char>,
+    offsets: Vec<usize>, // 文字の位置からバイト位置への対応表
+    pos: Option<usize>,  // 次に探索を始める文字の位置。Noneなら探索終了
+}
+
+impl<'r, 'h> Matches<'r, 'h> {
+    fn new(regex: &'r Regex, haystack: &'h str) -> Self {
+        // 評価器は文字単位で動くので、文字の位置からバイト位置への対応表を作っておく
+        let chars = haystack.chars().collect::<Vec<char>>();
+        let offsets = haystack
+            .char_indices()
+            .map(|(i, _)| i)
+            .chain(std::iter::once(haystack.len()))
+            .collect::<Vec<usize>>();
+
+        Matches {
+            regex,
+            haystack,
+            chars,
+            offsets,
+            pos: Some(0),
         }
     }
 }
 
+impl<'r, 'h> Matches<'r, 'h> {
+    /// 次のマッチを探し、キャプチャグループごとの範囲を返す
+    fn next_captures(&mut self) -> Option<Result<Captures<'h>, DynError>> {
+        let pos = self.pos?;
+        match self.regex.find_at(&self.chars, pos) {
+            Ok(Some(slots)) => {
+                // マッチした場合、スロット0と1には必ず値が入っている
+                let (start, end) = (slots[0].unwrap(), slots[1].unwrap());
+
+                // 空文字列にマッチした場合は、同じ位置で無限にマッチしないよう1文字進める
+                self.pos = if start == end {
+                    Some(end + 1).filter(|p| *p <= self.chars.len())
+                } else {
+                    Some(end)
+                };
+
+                // 文字単位の位置をバイト位置に変換する。空ループの検出用のスロットは除く
+                let slots = slots
+                    .into_iter()
+                    .take(self.regex.captures_len() * 2)
+                    .map(|s| s.map(|s| self.offsets[s]))
+                    .collect();
+                Some(Ok(Captures {
+                    haystack: self.haystack,
+                    slots,
+                    names: self.regex.names.clone(),
+                }))
+            }
+            Ok(None) => {
+                self.pos = None;
+                None
+            }
+            Err(e) => {
+                self.pos = None;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+impl<'r, 'h> Iterator for Matches<'r, 'h> {
+    type Item = Result<Match<'h>, DynError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // グループ0はマッチ全体なので必ず存在する
+        self.next_captures()
+            .map(|caps| caps.map(|caps| caps.get(0).unwrap()))
+    }
+}
+
 /// 正規表現と文字列をマッチングする
 ///
 /// # 利用例
@@ -41,56 +438,33 @@ impl Display for Instruction {
 ///
 /// # 戻り値
 ///
-/// マッチングに成功したらOk(true)
-/// マッチングに失敗したらOk(false)
+/// マッチングに成功したらOk((true, Some(マッチした範囲)))
+/// マッチングに失敗したらOk((false, None))
 /// 入力の正規表現が不正な値であったり、内部的な実装エラー時はErrを返す
 ///
-pub fn do_matching(
+/// 同じ正規表現で何度もマッチングする場合は、Regexを使うとコンパイルが一度で済む
+pub fn do_matching<'h>(
     expr: &str,
-    line: &str,
+    line: &'h str,
     is_depth: bool,
-) -> Result<(bool, Option<String>), DynError> {
-    let ast_state = parser::parse(expr)?;
-    let code = codegen::get_code(&ast_state.ast)?;
-    let line = line.chars().collect::<Vec<char>>();
-
-    if ast_state.has_hat {
-        // dollar
-        if evaluator::eval(&code, &line, is_depth, ast_state.has_dollar)? {
-            return Ok((true, Some(line.into_iter().collect::<String>())));
-        } else {
-            return Ok((false, None));
-        }
-    }
-
-    if ast_state.has_dollar {
-        for i in (0..line.len()).rev() {
-            let line = &line[i..];
-            if evaluator::eval(&code, line, is_depth, true)? {
-                return Ok((true, Some(line.into_iter().collect::<String>())));
-            }
-        }
-        return Ok((false, None));
-    }
-
-    for i in 0..line.len() {
-        let line = &line[i..];
-        if evaluator::eval(&code, line, is_depth, false)? {
-            return Ok((true, Some(line.into_iter().collect::<String>())));
-        }
-    }
-    Ok((false, None))
+) -> Result<(bool, Option<Match<'h>>), DynError> {
+    let regex = Regex::with_mode(expr, is_depth)?;
+    let m = regex.find(line)?;
+    Ok((m.is_some(), m))
 }
+
 /// 正規表現をパースしてコード生成し、
 /// ASTと命令列を標準出力に表示する
 pub fn print(expr: &str) -> Result<(), DynError> {
     println!("expr: {expr}");
-    let ast_state = parser::parse(expr)?;
+    let config = Config::default();
+    let ast_state = parser::parse(expr, config.unicode, config.case_insensitive)?;
     println!("AST: {:?}", ast_state.ast);
 
     println!();
     println!("code:");
-    let code = codegen::get_code(&ast_state.ast)?;
+    let capture_slots = (ast_state.captures + 1) * 2;
+    let (code, _) = codegen::get_code(&ast_state.ast, capture_slots, config.size_limit)?;
     for (n, c) in code.iter().enumerate() {
         println!("{:>04}: {c}", n);
     }
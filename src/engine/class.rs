@@ -0,0 +1,303 @@
+//! 文字クラスで使う文字の範囲の集合を扱う
+//! 範囲の集合は常に、ソート済みかつ重なりも隣接もしない(start, end)の列として扱う
+
+use super::unicode_tables;
+
+/// サロゲート領域の直前と直後の文字
+const BEFORE_SURROGATE: char = '\u{D7FF}';
+const AFTER_SURROGATE: char = '\u{E000}';
+
+/// 次の文字を返す。サロゲート領域は飛ばす
+fn next_char(c: char) -> Option<char> {
+    if c == BEFORE_SURROGATE {
+        Some(AFTER_SURROGATE)
+    } else {
+        char::from_u32(c as u32 + 1)
+    }
+}
+
+/// 前の文字を返す。サロゲート領域は飛ばす
+fn prev_char(c: char) -> Option<char> {
+    if c == AFTER_SURROGATE {
+        Some(BEFORE_SURROGATE)
+    } else {
+        (c as u32).checked_sub(1).and_then(char::from_u32)
+    }
+}
+
+/// 範囲の列をソートし、重なったり隣接したりしている範囲を1つにまとめる
+pub fn canonicalize(mut ranges: Vec<(char, char)>) -> Vec<(char, char)> {
+    ranges.sort_unstable();
+
+    let mut result: Vec<(char, char)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        if let Some(last) = result.last_mut() {
+            // 直前の範囲と重なるか、すぐ隣なら結合する
+            if next_char(last.1).is_none_or(|n| start <= n) {
+                if end > last.1 {
+                    last.1 = end;
+                }
+                continue;
+            }
+        }
+        result.push((start, end));
+    }
+    result
+}
+
+/// 範囲の集合の補集合を返す。rangesは正規化されている必要がある
+pub fn negate(ranges: &[(char, char)]) -> Vec<(char, char)> {
+    let mut result = Vec::with_capacity(ranges.len() + 1);
+    let mut start = Some('\0');
+
+    for &(s, e) in ranges {
+        if let Some(st) = start {
+            if st < s {
+                // prev_char(s)はstがs未満なので必ず存在する
+                result.push((st, prev_char(s).unwrap()));
+            }
+        }
+        start = next_char(e);
+    }
+
+    if let Some(st) = start {
+        result.push((st, char::MAX));
+    }
+    result
+}
+
+/// 2つの範囲の集合の和集合を返す
+pub fn union(a: &[(char, char)], b: &[(char, char)]) -> Vec<(char, char)> {
+    canonicalize([a, b].concat())
+}
+
+/// 2つの範囲の集合の積集合を返す。a、bは正規化されている必要がある
+pub fn intersection(a: &[(char, char)], b: &[(char, char)]) -> Vec<(char, char)> {
+    // ド・モルガンの法則で補集合の和集合の補集合として求める
+    negate(&union(&negate(a), &negate(b)))
+}
+
+/// aからbに含まれる文字を取り除いた差集合を返す。a、bは正規化されている必要がある
+pub fn difference(a: &[(char, char)], b: &[(char, char)]) -> Vec<(char, char)> {
+    intersection(a, &negate(b))
+}
+
+/// aとbのどちらか一方にだけ含まれる文字の集合を返す。a、bは正規化されている必要がある
+pub fn symmetric_difference(a: &[(char, char)], b: &[(char, char)]) -> Vec<(char, char)> {
+    union(&difference(a, b), &difference(b, a))
+}
+
+/// cが範囲の集合に含まれるならtrueを返す。rangesは正規化されている必要がある
+pub fn contains(ranges: &[(char, char)], c: char) -> bool {
+    ranges
+        .binary_search_by(|&(start, end)| {
+            if end < c {
+                std::cmp::Ordering::Less
+            } else if start > c {
+                std::cmp::Ordering::Greater
+            } else {
+                std::cmp::Ordering::Equal
+            }
+        })
+        .is_ok()
+}
+
+/// 大文字小文字を区別しない比較のため、Unicodeの単純な畳み込みでcを代表の文字に変換する
+/// 畳み込みの表にない文字はc自身を返す
+pub fn simple_fold(c: char) -> char {
+    match unicode_tables::SIMPLE_CASE_FOLDING.binary_search_by_key(&c, |&(from, _)| from) {
+        Ok(i) => unicode_tables::SIMPLE_CASE_FOLDING[i].1,
+        Err(_) => c,
+    }
+}
+
+/// 範囲の集合に、畳み込むと同じ文字になる文字をすべて加えた集合を返す
+/// 範囲の文字を1つずつ調べるのではなく、畳み込みの表を2回走査する
+/// rangesは正規化されている必要がある
+pub fn fold_ranges(ranges: &[(char, char)]) -> Vec<(char, char)> {
+    // 範囲に含まれる文字の畳み込み先を集める
+    let mut targets: Vec<char> = unicode_tables::SIMPLE_CASE_FOLDING
+        .iter()
+        .filter(|&&(from, to)| contains(ranges, from) || contains(ranges, to))
+        .map(|&(_, to)| to)
+        .collect();
+    targets.sort_unstable();
+    targets.dedup();
+
+    // 畳み込み先が同じ文字をすべて加える
+    let mut result = ranges.to_vec();
+    for &(from, to) in unicode_tables::SIMPLE_CASE_FOLDING {
+        if targets.binary_search(&to).is_ok() {
+            result.push((from, from));
+            result.push((to, to));
+        }
+    }
+    canonicalize(result)
+}
+
+/// \d \w \sとその否定(\D \W \S)について、範囲の集合と否定かどうかを返す
+/// unicodeが偽ならASCIIの範囲のみを対象にする
+pub fn perl_class(c: char, unicode: bool) -> Option<(&'static [(char, char)], bool)> {
+    let ranges: &[(char, char)] = match (c.to_ascii_lowercase(), unicode) {
+        ('d', true) => unicode_tables::PERL_DIGIT,
+        ('d', false) => &[('0', '9')],
+        ('s', true) => unicode_tables::PERL_SPACE,
+        // \t \n \v \f \r と空白
+        ('s', false) => &[('\t', '\r'), (' ', ' ')],
+        ('w', true) => unicode_tables::PERL_WORD,
+        ('w', false) => &[('0', '9'), ('A', 'Z'), ('_', '_'), ('a', 'z')],
+        _ => return None,
+    };
+    Some((ranges, c.is_ascii_uppercase()))
+}
+
+/// [[:alpha:]]などのPOSIXクラスの名前に対応する範囲の集合を返す
+/// POSIXクラスは常にASCIIの範囲のみを対象にする
+pub fn posix_class(name: &str) -> Option<&'static [(char, char)]> {
+    let ranges: &[(char, char)] = match name {
+        "alnum" => &[('0', '9'), ('A', 'Z'), ('a', 'z')],
+        "alpha" => &[('A', 'Z'), ('a', 'z')],
+        "blank" => &[('\t', '\t'), (' ', ' ')],
+        "cntrl" => &[('\0', '\x1F'), ('\x7F', '\x7F')],
+        "digit" => &[('0', '9')],
+        "graph" => &[('!', '~')],
+        "lower" => &[('a', 'z')],
+        "print" => &[(' ', '~')],
+        "punct" => &[('!', '/'), (':', '@'), ('[', '`'), ('{', '~')],
+        // \t \n \v \f \r と空白
+        "space" => &[('\t', '\r'), (' ', ' ')],
+        "upper" => &[('A', 'Z')],
+        "xdigit" => &[('0', '9'), ('A', 'F'), ('a', 'f')],
+        _ => return None,
+    };
+    Some(ranges)
+}
+
+/// \p{...}で比較するために名前を正規化する。小文字にして空白、_、-を取り除く
+fn normalize_property(name: &str) -> String {
+    name.chars()
+        .filter(|c| !c.is_whitespace() && *c != '_' && *c != '-')
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// \p{...}の名前に対応する範囲の集合を返す
+/// 名前は一般カテゴリ(LやLu、Letterなど)か用字(HiraganaやHiraなど)で、
+/// gc=やsc=、scx=を前に付けるといずれかに限定できる。大文字小文字と空白、_、-は区別しない
+/// 用字は、sc=ならScript、scx=や前に何も付けない場合はScript_Extensionsで判定する
+pub fn unicode_property(name: &str) -> Option<&'static [(char, char)]> {
+    let lookup = |table: &'static [(&str, &'static [(char, char)])], name: &str| {
+        table
+            .binary_search_by_key(&name, |&(n, _)| n)
+            .ok()
+            .map(|i| table[i].1)
+    };
+
+    let name = normalize_property(name);
+    match name.split_once('=') {
+        Some(("gc" | "generalcategory", value)) => lookup(unicode_tables::GENERAL_CATEGORY, value),
+        Some(("sc" | "script", value)) => lookup(unicode_tables::SCRIPT, value),
+        Some(("scx" | "scriptextensions", value)) => {
+            lookup(unicode_tables::SCRIPT_EXTENSIONS, value)
+        }
+        Some(_) => None,
+        None => lookup(unicode_tables::GENERAL_CATEGORY, &name)
+            .or_else(|| lookup(unicode_tables::SCRIPT_EXTENSIONS, &name)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        canonicalize, contains, difference, fold_ranges, intersection, negate, simple_fold,
+        symmetric_difference, unicode_property,
+    };
+
+    #[test]
+    fn test_canonicalize() {
+        let ranges = canonicalize(vec![('x', 'z'), ('a', 'c'), ('b', 'f'), ('g', 'g')]);
+        assert_eq!(vec![('a', 'g'), ('x', 'z')], ranges);
+    }
+
+    #[test]
+    fn test_negate() {
+        let ranges = negate(&[('a', 'c'), ('x', 'z')]);
+        assert_eq!(vec![('\0', '`'), ('d', 'w'), ('{', char::MAX)], ranges);
+        assert_eq!(vec![('\0', char::MAX)], negate(&[]));
+        assert!(negate(&[('\0', char::MAX)]).is_empty());
+
+        // サロゲート領域をまたいでも正しく補集合が取れる
+        let ranges = negate(&[('\0', '\u{D7FF}'), ('\u{E001}', char::MAX)]);
+        assert_eq!(vec![('\u{E000}', '\u{E000}')], ranges);
+    }
+
+    #[test]
+    fn test_set_operations() {
+        let a = [('a', 'f'), ('x', 'z')];
+        let b = [('d', 'y')];
+        assert_eq!(vec![('d', 'f'), ('x', 'y')], intersection(&a, &b));
+        assert_eq!(vec![('a', 'c'), ('z', 'z')], difference(&a, &b));
+        assert_eq!(
+            vec![('a', 'c'), ('g', 'w'), ('z', 'z')],
+            symmetric_difference(&a, &b)
+        );
+        assert!(intersection(&[('a', 'c')], &[('x', 'z')]).is_empty());
+    }
+
+    #[test]
+    fn test_contains() {
+        let ranges = [('a', 'c'), ('ぁ', 'ん')];
+        assert!(contains(&ranges, 'b'));
+        assert!(contains(&ranges, 'ぁ'));
+        assert!(contains(&ranges, 'ん'));
+        assert!(!contains(&ranges, 'd'));
+        assert!(!contains(&ranges, 'ア'));
+    }
+
+    #[test]
+    fn test_simple_fold() {
+        assert_eq!('a', simple_fold('A'));
+        assert_eq!('a', simple_fold('a'));
+        assert_eq!('k', simple_fold('\u{212A}')); // ケルビン記号
+        assert_eq!('σ', simple_fold('ς'));
+        assert_eq!('あ', simple_fold('あ'));
+    }
+
+    #[test]
+    fn test_fold_ranges() {
+        assert_eq!(vec![('A', 'C'), ('a', 'c')], fold_ranges(&[('a', 'c')]));
+        // 畳み込み先が同じ文字はすべて含まれる
+        assert_eq!(
+            vec![('K', 'K'), ('k', 'k'), ('\u{212A}', '\u{212A}')],
+            fold_ranges(&[('\u{212A}', '\u{212A}')])
+        );
+        assert_eq!(vec![('0', '9')], fold_ranges(&[('0', '9')]));
+    }
+
+    #[test]
+    fn test_unicode_property() {
+        let lu = unicode_property("Lu").unwrap();
+        assert!(contains(lu, 'A'));
+        assert!(!contains(lu, 'a'));
+        assert_eq!(Some(lu), unicode_property("uppercase letter"));
+        assert_eq!(Some(lu), unicode_property("gc=Uppercase_Letter"));
+
+        let kana = unicode_property("Katakana").unwrap();
+        assert!(contains(kana, 'ア'));
+        assert!(contains(kana, 'ー'));
+        assert_eq!(Some(kana), unicode_property("scx=Kana"));
+
+        // sc=はScriptで判定するので、複数の用字で使われる文字は含まない
+        let sc_kana = unicode_property("sc=Kana").unwrap();
+        assert!(contains(sc_kana, 'ア'));
+        assert!(!contains(sc_kana, 'ー'));
+        let sc_han = unicode_property("script=Han").unwrap();
+        assert!(contains(sc_han, '漢'));
+        assert!(!contains(sc_han, '、'));
+        assert!(contains(unicode_property("scx=Han").unwrap(), '、'));
+
+        assert_eq!(None, unicode_property("Klingon"));
+        assert_eq!(None, unicode_property("gc=Greek"));
+        assert_eq!(None, unicode_property("foo=L"));
+    }
+}
@@ -1,8 +1,9 @@
-use super::{parser::AST, Instruction};
+use super::{class, parser::AST, Assertion, Instruction};
 use crate::helper::safe_add;
 use std::{
     error::Error,
     fmt::{self, Display},
+    mem::{self, take},
 };
 
 #[derive(Debug)]
@@ -11,30 +12,57 @@ pub enum CodeGenError {
     FailStar,
     FailOr,
     FailQuestion,
+    FailRepeat,
+    FailLazy,
+    FailSubProgram,
+    FailConditional,
+    FailCall,                 // 呼び出すグループのコードが生成されていない
+    SizeLimitExceeded(usize), // 生成する命令数が上限を超えた
 }
 
 impl Display for CodeGenError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "CodeGenError: {:?}", self)
+        match self {
+            CodeGenError::SizeLimitExceeded(limit) => {
+                write!(f, "CodeGenError: size limit exceeded: limit = {limit}")
+            }
+            _ => write!(f, "CodeGenError: {:?}", self),
+        }
     }
 }
 impl Error for CodeGenError {}
 
 #[derive(Default, Debug)]
 struct Generator {
-    pc: usize,               //次に生成するアセンブリ命令のアドレス
-    insts: Vec<Instruction>, // 命令の一覧。get_codeではこれを返す
+    pc: usize,                           //次に生成するアセンブリ命令のアドレス
+    insts: Vec<Instruction>,             // 命令の一覧。get_codeではこれを返す
+    size_limit: usize,                   // 生成する命令数の上限
+    reverse: bool,                       // 後読みの中では、後ろ向きに評価するため逆順に生成する
+    groups: Vec<Option<(usize, usize)>>, // グループごとの、開始と終了を保存する命令のアドレス
+    calls: Vec<usize>,                   // 呼び出し先を後で設定するcallのアドレス
+    n_slots: usize, // 使用するスロットの数。空ループの検出用のスロットを割り当てるたびに増やす
 }
 
 impl Generator {
     fn inc_pc(&mut self) -> Result<(), CodeGenError> {
         // クロージャでエラーを返すようにすると、メモリのアロケーションを遅延することができる
-        safe_add(&mut self.pc, &1, || CodeGenError::PCOverFlow)
+        safe_add(&mut self.pc, &1, || CodeGenError::PCOverFlow)?;
+        // {n,m}の展開などで命令数が爆発しないよう、上限を超えたら打ち切る
+        if self.pc > self.size_limit {
+            return Err(CodeGenError::SizeLimitExceeded(self.size_limit));
+        }
+        Ok(())
     }
 
     fn gen_seq(&mut self, exprs: &[AST]) -> Result<(), CodeGenError> {
-        for e in exprs {
-            self.gen_expr(e)?;
+        if self.reverse {
+            for e in exprs.iter().rev() {
+                self.gen_expr(e)?;
+            }
+        } else {
+            for e in exprs {
+                self.gen_expr(e)?;
+            }
         }
         Ok(())
     }
@@ -46,18 +74,73 @@ impl Generator {
         Ok(())
     }
 
+    /// 大文字小文字を区別しない文字は、比較の前に入力を畳み込む命令にする
+    fn gen_char_fold(&mut self, c: char) -> Result<(), CodeGenError> {
+        self.insts
+            .push(Instruction::CharFold(class::simple_fold(c)));
+        self.inc_pc()?;
+        Ok(())
+    }
+
+    /// 否定の文字クラスはここで補集合を取り、範囲の集合だけを持つ命令にする
+    /// 大文字小文字を区別しない場合は、補集合を取る前に畳み込みで対応する文字を加える
+    fn gen_class(
+        &mut self,
+        ranges: &[(char, char)],
+        negated: bool,
+        case_insensitive: bool,
+    ) -> Result<(), CodeGenError> {
+        let ranges = if case_insensitive {
+            class::fold_ranges(ranges)
+        } else {
+            ranges.to_vec()
+        };
+        let ranges = if negated {
+            class::negate(&ranges)
+        } else {
+            ranges
+        };
+        self.insts.push(Instruction::Class(ranges));
+        self.inc_pc()?;
+        Ok(())
+    }
+
+    fn gen_assert(&mut self, assertion: Assertion) -> Result<(), CodeGenError> {
+        self.insts.push(Instruction::Assert(assertion));
+        self.inc_pc()?;
+        Ok(())
+    }
+
     fn gen_dot(&mut self) -> Result<(), CodeGenError> {
         self.insts.push(Instruction::Dot);
         self.inc_pc()?;
         Ok(())
     }
 
+    fn gen_any_char(&mut self) -> Result<(), CodeGenError> {
+        self.insts.push(Instruction::AnyChar);
+        self.inc_pc()?;
+        Ok(())
+    }
+
+    /// addrにあるsplitの分岐の優先度を入れ替える
+    /// 非貪欲な量指定子は、貪欲な場合のコードを生成してからこれで入れ替える
+    fn swap_split(&mut self, addr: usize) -> Result<(), CodeGenError> {
+        if let Some(Instruction::Split(l1, l2)) = self.insts.get_mut(addr) {
+            mem::swap(l1, l2);
+            Ok(())
+        } else {
+            Err(CodeGenError::FailLazy)
+        }
+    }
+
     /// ```text
     /// split L1, L2
     /// L1: eのコード
     /// L2:
     /// ```
-    fn gen_question(&mut self, e: &AST) -> Result<(), CodeGenError> {
+    /// greedyが偽(??)のときはsplit L2, L1とし、eを飛ばす方を優先する
+    fn gen_question(&mut self, e: &AST, greedy: bool) -> Result<(), CodeGenError> {
         // split L1, L2
         let split_addr = self.pc;
         self.inc_pc()?;
@@ -70,10 +153,14 @@ impl Generator {
         // L2の値を設定
         if let Some(Instruction::Split(_, l2)) = self.insts.get_mut(split_addr) {
             *l2 = self.pc;
-            Ok(())
         } else {
-            Err(CodeGenError::FailQuestion)
+            return Err(CodeGenError::FailQuestion);
+        }
+
+        if !greedy {
+            self.swap_split(split_addr)?;
         }
+        Ok(())
     }
 
     /// ```text
@@ -82,23 +169,59 @@ impl Generator {
     ///     jump L1
     /// L3:
     /// ```
-    fn gen_star(&mut self, e: &AST) -> Result<(), CodeGenError> {
+    /// greedyが偽(*?)のときはsplit L3, L2とし、ループを抜ける方を優先する
+    ///
+    /// eが空文字列にマッチしうる場合は、繰り返しの開始位置を新しいスロットkに保存し、
+    /// 文字を消費せずに終わった繰り返しではループを抜ける。こうしないと深さ優先探索が同じ位置で回り続ける
+    /// ```text
+    /// L1: split L2, L3
+    /// L2: save k
+    ///     eのコード
+    ///     progress k, L3
+    ///     jump L1
+    /// L3:
+    /// ```
+    fn gen_star(&mut self, e: &AST, greedy: bool) -> Result<(), CodeGenError> {
         let l1 = self.pc;
         self.inc_pc()?;
         let split = Instruction::Split(self.pc, 0);
         self.insts.push(split);
 
-        self.gen_expr(e)?;
+        let progress_addr = if can_be_empty(e) {
+            let slot = self.n_slots;
+            safe_add(&mut self.n_slots, &1, || CodeGenError::PCOverFlow)?;
+            self.inc_pc()?;
+            self.insts.push(Instruction::Save(slot));
+            self.gen_expr(e)?;
+            let progress_addr = self.pc;
+            self.inc_pc()?;
+            self.insts.push(Instruction::Progress(slot, 0)); // L3を仮に0と設定
+            Some(progress_addr)
+        } else {
+            self.gen_expr(e)?;
+            None
+        };
 
         self.inc_pc()?;
         self.insts.push(Instruction::Jump(l1));
 
         if let Some(Instruction::Split(_, l3)) = self.insts.get_mut(l1) {
             *l3 = self.pc;
-            Ok(())
         } else {
-            Err(CodeGenError::FailStar)
+            return Err(CodeGenError::FailStar);
         }
+        if let Some(addr) = progress_addr {
+            if let Some(Instruction::Progress(_, l3)) = self.insts.get_mut(addr) {
+                *l3 = self.pc;
+            } else {
+                return Err(CodeGenError::FailStar);
+            }
+        }
+
+        if !greedy {
+            self.swap_split(l1)?;
+        }
+        Ok(())
     }
 
     /// ```text
@@ -106,16 +229,278 @@ impl Generator {
     ///     split L1, L2
     /// L2:
     /// ```
-    fn gen_plus(&mut self, e: &AST) -> Result<(), CodeGenError> {
+    /// greedyが偽(+?)のときはsplit L2, L1とし、ループを抜ける方を優先する
+    /// eが空文字列にマッチしうる場合は、1回目の空のマッチは許すようにee*として生成する
+    fn gen_plus(&mut self, e: &AST, greedy: bool) -> Result<(), CodeGenError> {
+        if can_be_empty(e) {
+            self.gen_expr(e)?;
+            return self.gen_star(e, greedy);
+        }
+
         let l1 = self.pc;
         self.gen_expr(e)?;
 
         self.inc_pc()?;
-        let split = Instruction::Split(l1, self.pc);
+        let split = if greedy {
+            Instruction::Split(l1, self.pc)
+        } else {
+            Instruction::Split(self.pc, l1)
+        };
         self.insts.push(split);
         Ok(())
     }
 
+    /// {n}, {n,}, {n,m}のコード生成器
+    /// eをn回並べた後、上限がなければe*を、あればeを入れ子の?で(m - n)回並べる
+    /// ```text
+    ///     eのコード (n回)
+    ///     split L1, L3
+    /// L1: eのコード
+    ///     split L2, L3
+    /// L2: eのコード
+    ///     ...
+    /// L3:
+    /// ```
+    /// 入れ子にしておくと、途中のeが失敗した時点で残りをすべて飛ばせる
+    /// greedyが偽({n,m}?)のときは各splitの優先度を入れ替える
+    fn gen_repeat(
+        &mut self,
+        e: &AST,
+        min: usize,
+        max: Option<usize>,
+        greedy: bool,
+    ) -> Result<(), CodeGenError> {
+        for _ in 0..min {
+            self.gen_expr(e)?;
+        }
+
+        let max = if let Some(max) = max {
+            max
+        } else {
+            return self.gen_star(e, greedy);
+        };
+
+        let mut split_addrs = Vec::new();
+        for _ in min..max {
+            split_addrs.push(self.pc);
+            self.inc_pc()?;
+            self.insts.push(Instruction::Split(self.pc, 0)); // L3は仮に0と設定
+            self.gen_expr(e)?;
+        }
+
+        // L3の値を設定
+        for addr in split_addrs {
+            if let Some(Instruction::Split(_, l3)) = self.insts.get_mut(addr) {
+                *l3 = self.pc;
+            } else {
+                return Err(CodeGenError::FailRepeat);
+            }
+
+            if !greedy {
+                self.swap_split(addr)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// キャプチャグループのコード生成器
+    /// グループnの開始位置をスロット2n、終了位置をスロット2n+1に保存する
+    /// ```text
+    ///     save 2n
+    ///     eのコード
+    ///     save 2n+1
+    /// ```
+    fn gen_capture(&mut self, group: usize, e: &AST) -> Result<(), CodeGenError> {
+        let slot = group.checked_mul(2).ok_or(CodeGenError::PCOverFlow)?;
+        // 逆順に生成するときは、終了位置を先に保存する
+        let (first, last) = if self.reverse {
+            (slot + 1, slot)
+        } else {
+            (slot, slot + 1)
+        };
+        let start = self.pc;
+        self.insts.push(Instruction::Save(first));
+        self.inc_pc()?;
+
+        self.gen_expr(e)?;
+
+        let end = self.pc;
+        self.insts.push(Instruction::Save(last));
+        self.inc_pc()?;
+
+        // 呼び出し先として使うため、最初に前向きに生成したときのアドレスを覚えておく
+        if !self.reverse {
+            if self.groups.len() <= group {
+                self.groups.resize(group + 1, None);
+            }
+            self.groups[group].get_or_insert((start, end));
+        }
+        Ok(())
+    }
+
+    /// (?R)や(?1)の呼び出しのコード生成器
+    /// 呼び出し先のアドレスは、すべてのコードを生成した後にfill_callsで設定する
+    fn gen_call(&mut self, group: usize) -> Result<(), CodeGenError> {
+        self.calls.push(self.pc);
+        self.insts.push(Instruction::Call {
+            group,
+            start: 0,
+            end: 0,
+        });
+        self.inc_pc()?;
+        Ok(())
+    }
+
+    /// 呼び出されているのに前向きのコードがないグループのコードを、最後のmatchの後ろに生成する
+    /// 後読みの中や{0}の中にしかないグループは、前向きに評価できるコードが生成されていないため
+    /// ここで生成したコードには呼び出しからしか到達しない
+    fn gen_called_groups(&mut self, ast: &AST) -> Result<(), CodeGenError> {
+        // 生成したコードの中の呼び出しもself.callsに追加されるので、添字で辿る
+        let mut i = 0;
+        while let Some(&addr) = self.calls.get(i) {
+            i += 1;
+            let Some(Instruction::Call { group, .. }) = self.insts.get(addr) else {
+                return Err(CodeGenError::FailCall);
+            };
+            let group = *group;
+            if group == 0 || matches!(self.groups.get(group), Some(Some(_))) {
+                continue;
+            }
+
+            let e = find_capture(ast, group).ok_or(CodeGenError::FailCall)?;
+            self.reverse = false;
+            self.gen_capture(group, e)?;
+        }
+        Ok(())
+    }
+
+    /// callに呼び出し先のグループのアドレスを設定する
+    /// グループ0はパターン全体なので、先頭から最後のmatch(match_addr)の手前までとする
+    fn fill_calls(&mut self, match_addr: usize) -> Result<(), CodeGenError> {
+        for addr in take(&mut self.calls) {
+            let Some(Instruction::Call { group, start, end }) = self.insts.get_mut(addr) else {
+                return Err(CodeGenError::FailCall);
+            };
+            (*start, *end) = match *group {
+                0 => (0, match_addr),
+                g => self
+                    .groups
+                    .get(g)
+                    .copied()
+                    .flatten()
+                    .ok_or(CodeGenError::FailCall)?,
+            };
+        }
+        Ok(())
+    }
+
+    /// 大文字小文字を区別しない後方参照は、畳み込んで比較する命令にする
+    fn gen_backref(&mut self, group: usize, case_insensitive: bool) -> Result<(), CodeGenError> {
+        if case_insensitive {
+            self.insts.push(Instruction::BackrefFold(group));
+        } else {
+            self.insts.push(Instruction::Backref(group));
+        }
+        self.inc_pc()?;
+        Ok(())
+    }
+
+    /// 部分プログラムを持つ命令のコード生成器
+    ///     head L1
+    ///     eのコード
+    ///     match
+    /// L1: ...
+    /// のようなコードを生成する。headのnextは仮で0としておき、ここでL1を設定する
+    /// headの次からmatchまでを部分プログラムとして評価し、結果に応じてL1へ進むか失敗する
+    /// reverseが真ならeを逆順に生成する
+    fn gen_sub_program(
+        &mut self,
+        head: Instruction,
+        e: &AST,
+        reverse: bool,
+    ) -> Result<(), CodeGenError> {
+        let head_addr = self.pc;
+        self.insts.push(head);
+        self.inc_pc()?;
+
+        let prev_reverse = mem::replace(&mut self.reverse, reverse);
+        self.gen_expr(e)?;
+        self.reverse = prev_reverse;
+
+        // 部分プログラムの終わり
+        self.insts.push(Instruction::Match);
+        self.inc_pc()?;
+
+        match self.insts.get_mut(head_addr) {
+            Some(
+                Instruction::LookAhead { next, .. }
+                | Instruction::LookBehind { next, .. }
+                | Instruction::Atomic { next },
+            ) => *next = self.pc,
+            _ => return Err(CodeGenError::FailSubProgram),
+        }
+
+        Ok(())
+    }
+
+    /// 先読みと後読みのコード生成器
+    /// 後読みのeは後ろ向きに評価するため、逆順に生成する
+    fn gen_look_around(&mut self, e: &AST, negated: bool, ahead: bool) -> Result<(), CodeGenError> {
+        if ahead {
+            self.gen_sub_program(Instruction::LookAhead { negated, next: 0 }, e, false)
+        } else {
+            self.gen_sub_program(Instruction::LookBehind { negated, next: 0 }, e, true)
+        }
+    }
+
+    /// アトミックグループのコード生成器
+    /// 評価の向きは外側と同じにする
+    fn gen_atomic(&mut self, e: &AST) -> Result<(), CodeGenError> {
+        self.gen_sub_program(Instruction::Atomic { next: 0 }, e, self.reverse)
+    }
+
+    /// 条件分岐のコード生成器
+    ///     condjump group, L1
+    ///     yesのコード
+    ///     jmp L2
+    /// L1: noのコード
+    /// L2: ...
+    /// のようなコードを生成する
+    fn gen_conditional(
+        &mut self,
+        group: usize,
+        yes: &AST,
+        no: Option<&AST>,
+    ) -> Result<(), CodeGenError> {
+        let cond_addr = self.pc;
+        self.insts.push(Instruction::CondJump(group, 0)); // L1を仮で0とする
+        self.inc_pc()?;
+
+        self.gen_expr(yes)?;
+
+        let jmp_addr = self.pc;
+        self.insts.push(Instruction::Jump(0)); // L2を仮で0とする
+        self.inc_pc()?;
+
+        if let Some(Instruction::CondJump(_, l1)) = self.insts.get_mut(cond_addr) {
+            *l1 = self.pc;
+        } else {
+            return Err(CodeGenError::FailConditional);
+        }
+
+        if let Some(no) = no {
+            self.gen_expr(no)?;
+        }
+
+        if let Some(Instruction::Jump(l2)) = self.insts.get_mut(jmp_addr) {
+            *l2 = self.pc;
+        } else {
+            return Err(CodeGenError::FailConditional);
+        }
+
+        Ok(())
+    }
+
     /// or演算子のコード生成器
     ///     split L1, L2
     /// L1: e1のコード
@@ -162,11 +547,40 @@ impl Generator {
     fn gen_expr(&mut self, ast: &AST) -> Result<(), CodeGenError> {
         match ast {
             AST::Char(c) => self.gen_char(*c)?,
+            AST::CharFold(c) => self.gen_char_fold(*c)?,
             AST::Dot => self.gen_dot()?,
+            AST::AnyChar => self.gen_any_char()?,
+            AST::Class {
+                ranges,
+                negated,
+                case_insensitive,
+            } => self.gen_class(ranges, *negated, *case_insensitive)?,
             AST::Or(e1, e2) => self.gen_or(e1, e2)?,
-            AST::Plus(e) => self.gen_plus(e)?,
-            AST::Star(e) => self.gen_star(e)?,
-            AST::Question(e) => self.gen_question(e)?,
+            AST::Plus(e) => self.gen_plus(e, true)?,
+            AST::Star(e) => self.gen_star(e, true)?,
+            AST::Question(e) => self.gen_question(e, true)?,
+            AST::PlusLazy(e) => self.gen_plus(e, false)?,
+            AST::StarLazy(e) => self.gen_star(e, false)?,
+            AST::QuestionLazy(e) => self.gen_question(e, false)?,
+            AST::Repeat {
+                ast,
+                min,
+                max,
+                greedy,
+            } => self.gen_repeat(ast, *min, *max, *greedy)?,
+            AST::Capture(group, e) => self.gen_capture(*group, e)?,
+            AST::Assert(assertion) => self.gen_assert(*assertion)?,
+            AST::LookAhead { ast, negated } => self.gen_look_around(ast, *negated, true)?,
+            AST::LookBehind { ast, negated } => self.gen_look_around(ast, *negated, false)?,
+            AST::Backref {
+                group,
+                case_insensitive,
+            } => self.gen_backref(*group, *case_insensitive)?,
+            AST::Atomic(e) => self.gen_atomic(e)?,
+            AST::Call(group) => self.gen_call(*group)?,
+            AST::Conditional { group, yes, no } => {
+                self.gen_conditional(*group, yes, no.as_deref())?
+            }
             AST::Seq(v) => self.gen_seq(v)?,
         }
         Ok(())
@@ -174,15 +588,84 @@ impl Generator {
 
     fn gen_code(&mut self, ast: &AST) -> Result<(), CodeGenError> {
         self.gen_expr(ast)?;
-        self.inc_pc()?;
         // 最後にmatchをおいて終わり
+        let match_addr = self.pc;
+        self.inc_pc()?;
         self.insts.push(Instruction::Match);
+        self.gen_called_groups(ast)?;
+        self.fill_calls(match_addr)?;
         Ok(())
     }
 }
 
-pub fn get_code(ast: &AST) -> Result<Vec<Instruction>, CodeGenError> {
-    let mut generator = Generator::default();
+/// astの中からgroup番目のキャプチャグループを探し、その中の式を返す
+fn find_capture(ast: &AST, group: usize) -> Option<&AST> {
+    match ast {
+        AST::Capture(g, e) if *g == group => Some(e),
+        AST::Capture(_, e)
+        | AST::Plus(e)
+        | AST::Star(e)
+        | AST::Question(e)
+        | AST::PlusLazy(e)
+        | AST::StarLazy(e)
+        | AST::QuestionLazy(e)
+        | AST::Atomic(e)
+        | AST::Repeat { ast: e, .. }
+        | AST::LookAhead { ast: e, .. }
+        | AST::LookBehind { ast: e, .. } => find_capture(e, group),
+        AST::Or(e1, e2) => find_capture(e1, group).or_else(|| find_capture(e2, group)),
+        AST::Conditional { yes, no, .. } => {
+            find_capture(yes, group).or_else(|| no.as_deref().and_then(|e| find_capture(e, group)))
+        }
+        AST::Seq(v) => v.iter().find_map(|e| find_capture(e, group)),
+        AST::Char(_)
+        | AST::CharFold(_)
+        | AST::Dot
+        | AST::AnyChar
+        | AST::Class { .. }
+        | AST::Assert(_)
+        | AST::Backref { .. }
+        | AST::Call(_) => None,
+    }
+}
+
+/// astが空文字列にマッチしうるならtrueを返す
+/// 文字を消費しない命令や、長さのわからない後方参照と呼び出しは空文字列にマッチしうるものとする
+fn can_be_empty(ast: &AST) -> bool {
+    match ast {
+        AST::Char(_) | AST::CharFold(_) | AST::Dot | AST::AnyChar | AST::Class { .. } => false,
+        AST::Plus(e) | AST::PlusLazy(e) | AST::Capture(_, e) | AST::Atomic(e) => can_be_empty(e),
+        AST::Repeat { ast, min, .. } => *min == 0 || can_be_empty(ast),
+        AST::Or(e1, e2) => can_be_empty(e1) || can_be_empty(e2),
+        AST::Seq(v) => v.iter().all(can_be_empty),
+        AST::Conditional { yes, no, .. } => {
+            can_be_empty(yes) || no.as_deref().is_none_or(can_be_empty)
+        }
+        AST::Star(_)
+        | AST::StarLazy(_)
+        | AST::Question(_)
+        | AST::QuestionLazy(_)
+        | AST::Assert(_)
+        | AST::LookAhead { .. }
+        | AST::LookBehind { .. }
+        | AST::Backref { .. }
+        | AST::Call(_) => true,
+    }
+}
+
+/// ASTから命令列を生成し、命令列と使用するスロットの数を返す
+/// キャプチャにはn_slots個のスロットを使い、それ以降を空ループの検出用に割り当てる
+/// size_limitは生成する命令数の上限
+pub fn get_code(
+    ast: &AST,
+    n_slots: usize,
+    size_limit: usize,
+) -> Result<(Vec<Instruction>, usize), CodeGenError> {
+    let mut generator = Generator {
+        size_limit,
+        n_slots,
+        ..Default::default()
+    };
     generator.gen_code(ast)?;
-    Ok(generator.insts)
+    Ok((generator.insts, generator.n_slots))
 }
@@ -1,101 +1,893 @@
-use super::Instruction;
+use super::{class, Assertion, Instruction};
 use crate::helper::safe_add;
 use std::{
-    // collections::VecDeque,
     error::Error,
     fmt::{self, Display},
+    mem,
 };
 
 #[derive(Debug)]
 pub enum EvalError {
     PCOverFlow,
     SPOverFlow,
-    // 以下の2つは評価器にエラーがあるときに発生する
+    // 以下は評価器にエラーがあるときに発生する
     InvalidPC,
+    InvalidSlot,
     // InvalidContext,
+    Unsupported(&'static str), // 幅優先探索では評価できない機能。&strは機能の名前
+    RecursionLimitExceeded(usize), // (?R)や(?1)の呼び出しの深さが上限を超えた
+    StackLimitExceeded(usize), // 深さ優先探索の後戻りのためのスタックが上限を超えた
 }
 
 impl Display for EvalError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "CodeGenError: {:?}", self)
+        match self {
+            EvalError::Unsupported(feature) => write!(
+                f,
+                "EvalError: {feature} is not supported by breadth-first evaluation, use depth-first evaluation"
+            ),
+            EvalError::RecursionLimitExceeded(limit) => {
+                write!(f, "EvalError: recursion limit exceeded: limit = {limit}")
+            }
+            EvalError::StackLimitExceeded(limit) => {
+                write!(f, "EvalError: backtrack stack limit exceeded: limit = {limit}")
+            }
+            _ => write!(f, "CodeGenError: {:?}", self),
+        }
     }
 }
 
 impl Error for EvalError {}
 
+/// キャプチャの位置を保存するスロット
+/// スロット2nにグループnの開始位置、2n+1に終了位置を保存する。グループ0はマッチ全体
+pub type Slots = Vec<Option<usize>>;
+
+/// slotにspを保存する
+fn save(slots: &mut Slots, slot: usize, sp: usize) -> Result<(), EvalError> {
+    if let Some(s) = slots.get_mut(slot) {
+        *s = Some(sp);
+        Ok(())
+    } else {
+        Err(EvalError::InvalidSlot)
+    }
+}
+
+/// cが単語を構成する文字(\w)ならtrueを返す
+fn is_word_char(c: Option<&char>, unicode: bool) -> bool {
+    match (c, class::perl_class('w', unicode)) {
+        (Some(c), Some((ranges, _))) => class::contains(ranges, *c),
+        _ => false,
+    }
+}
+
+/// lineのsp文字目の直前の位置がassertionを満たすならtrueを返す
+fn is_asserted(assertion: Assertion, line: &[char], sp: usize) -> bool {
+    let is_boundary = |unicode| {
+        let prev = sp.checked_sub(1).and_then(|i| line.get(i));
+        is_word_char(prev, unicode) != is_word_char(line.get(sp), unicode)
+    };
+
+    match assertion {
+        Assertion::StartText => sp == 0,
+        Assertion::EndText => sp == line.len(),
+        Assertion::StartLine => sp == 0 || line.get(sp - 1) == Some(&'\n'),
+        Assertion::EndLine => sp == line.len() || line.get(sp) == Some(&'\n'),
+        Assertion::WordBoundary { unicode } => is_boundary(unicode),
+        Assertion::NotWordBoundary { unicode } => !is_boundary(unicode),
+    }
+}
+
+/// 幅優先探索では評価できない命令なら、その機能の名前を返す
+/// 部分プログラムの結果で分岐する命令は、スレッドを1文字ずつ同時に進める幅優先探索では扱えない
+fn unsupported_by_width(inst: &Instruction) -> Option<&'static str> {
+    match inst {
+        Instruction::LookAhead { .. } => Some("lookahead"),
+        Instruction::LookBehind { .. } => Some("lookbehind"),
+        Instruction::Backref(_) | Instruction::BackrefFold(_) => Some("backreference"),
+        Instruction::Atomic { .. } => Some("atomic group"),
+        Instruction::CondJump(_, _) => Some("conditional group"),
+        Instruction::Call { .. } => Some("recursion"),
+        _ => None,
+    }
+}
+
+/// 命令列を幅優先探索で評価できるか調べ、できなければEvalError::Unsupportedを返す
+pub fn check_width(inst: &[Instruction]) -> Result<(), EvalError> {
+    match inst.iter().find_map(unsupported_by_width) {
+        Some(feature) => Err(EvalError::Unsupported(feature)),
+        None => Ok(()),
+    }
+}
+
+/// 評価の向きに応じて、lineのsp文字目の位置から次に読む文字を返す
+/// reverseが真なら後ろ向きに評価しているので、直前の文字を返す
+fn read_char(line: &[char], sp: usize, reverse: bool) -> Option<&char> {
+    if reverse {
+        sp.checked_sub(1).and_then(|i| line.get(i))
+    } else {
+        line.get(sp)
+    }
+}
+
+/// 評価の向きに応じて、spを1文字進める
+fn step(sp: &mut usize, reverse: bool) -> Result<(), EvalError> {
+    if reverse {
+        // read_charで文字を読めているので、0未満にはならない
+        *sp = sp.checked_sub(1).ok_or(EvalError::SPOverFlow)?;
+        Ok(())
+    } else {
+        safe_add(sp, &1, || EvalError::SPOverFlow)
+    }
+}
+
+/// groupのグループがキャプチャした文字列が、評価の向きに応じてspから続くか調べる
+/// 続くなら文字列を読んだ後のspを返す。グループがキャプチャしていなければNoneを返す
+/// foldが真なら畳み込んだ文字同士で比較する
+fn match_backref(
+    line: &[char],
+    slots: &Slots,
+    group: usize,
+    sp: usize,
+    reverse: bool,
+    fold: bool,
+) -> Result<Option<usize>, EvalError> {
+    let start = group.checked_mul(2).ok_or(EvalError::InvalidSlot)?;
+    let captured = match (slots.get(start), slots.get(start + 1)) {
+        (Some(Some(s)), Some(Some(e))) if s <= e => &line[*s..*e],
+        (Some(_), Some(_)) => return Ok(None),
+        _ => return Err(EvalError::InvalidSlot),
+    };
+
+    // 比較する範囲と、読んだ後のsp
+    let (target, next_sp) = if reverse {
+        match sp.checked_sub(captured.len()) {
+            Some(s) => (&line[s..sp], s),
+            None => return Ok(None),
+        }
+    } else {
+        match line.get(sp..sp + captured.len()) {
+            Some(t) => (t, sp + captured.len()),
+            None => return Ok(None),
+        }
+    };
+
+    let is_same = if fold {
+        captured
+            .iter()
+            .zip(target)
+            .all(|(a, b)| class::simple_fold(*a) == class::simple_fold(*b))
+    } else {
+        captured == target
+    };
+    Ok(is_same.then_some(next_sp))
+}
+
+/// 深さ優先探索で使う資源の上限
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub recursion: usize,   // 部分プログラムの入れ子の深さの上限
+    pub memo_budget: usize, // 訪れた状態を記録するビット集合の大きさ(バイト)の上限
+    pub stack: usize,       // 後戻りのためにスタックに積む情報の数の上限
+}
+
+/// 深さ優先探索で評価している部分プログラムの情報
+#[derive(Debug, Clone, Copy)]
+struct Frame {
+    reverse: bool,      // 後読みの部分プログラムなら真。spから後ろ向きに文字を読む
+    end: Option<usize>, // (?1)で呼び出したグループの終わりの位置。ここに来たらマッチとする
+    depth: usize,       // 部分プログラムの入れ子の深さ
+    limits: Limits,
+}
+
+impl Frame {
+    /// 部分プログラムを評価するための情報を返す
+    /// 部分プログラムはeval_depthを再帰呼び出しして評価するので、lookaroundやアトミックグループも
+    /// (?R)や(?1)の呼び出しと同じく入れ子の深さに数え、上限を超えたらスタックが溢れる前にエラーにする
+    fn enter(self, reverse: bool, end: Option<usize>) -> Result<Frame, EvalError> {
+        if self.depth >= self.limits.recursion {
+            return Err(EvalError::RecursionLimitExceeded(self.limits.recursion));
+        }
+        Ok(Frame {
+            reverse,
+            end,
+            depth: self.depth + 1,
+            limits: self.limits,
+        })
+    }
+
+    /// lookaroundやアトミックグループの部分プログラムを評価するための情報を返す
+    /// 部分プログラムは自身のMatchで終わるので、呼び出したグループの終わりは引き継がない
+    fn sub_program(self, reverse: bool) -> Result<Frame, EvalError> {
+        self.enter(reverse, None)
+    }
+}
+
+/// 空文字列にマッチしうる繰り返し(progressのあるループ)の入れ子の構造
+/// progressの結果は、繰り返しの開始位置を保存したスロットが現在の位置と等しいかで変わる
+/// そのため訪れた状態を記録する際は、pcを囲むループのうち現在の位置で繰り返しを始めたものの数も区別する
+/// 外側のループが現在の位置で始まっていれば、その内側のループも同じ位置で始まっているので、数だけで区別できる
+struct Loops {
+    inner: Vec<Option<usize>>, // 命令ごとの、それを囲む最も内側のループ
+    loops: Vec<(usize, Option<usize>)>, // ループごとの、開始位置を保存するスロットと外側のループ
+    offsets: Vec<usize>, // 命令ごとの状態の番号の始まり。命令は囲むループの数 + 1個の状態を持つ
+}
+
+impl Loops {
+    /// progress k, L3 と、その繰り返しの先頭のsave kの間を本体として、ループの入れ子を調べる
+    fn new(inst: &[Instruction]) -> Result<Loops, EvalError> {
+        // (本体の先頭, 本体の末尾のprogress, スロット)
+        let mut bodies = Vec::new();
+        for (pc, i) in inst.iter().enumerate() {
+            if let Instruction::Progress(slot, _) = i {
+                let save = inst[..pc]
+                    .iter()
+                    .rposition(|i| matches!(i, Instruction::Save(s) if s == slot))
+                    .ok_or(EvalError::InvalidSlot)?;
+                bodies.push((save + 1, pc, *slot));
+            }
+        }
+        // 外側のループほど本体の先頭が前にある
+        bodies.sort_unstable();
+
+        let mut inner = Vec::with_capacity(inst.len());
+        let mut loops = Vec::with_capacity(bodies.len());
+        let mut offsets = Vec::with_capacity(inst.len() + 1);
+        let mut open: Vec<(usize, usize)> = Vec::new(); // 開いているループの(番号, 本体の末尾)
+        let mut bodies = bodies.into_iter().peekable();
+        let mut n_states = 0;
+        for pc in 0..inst.len() {
+            while open.last().is_some_and(|(_, last)| *last < pc) {
+                open.pop();
+            }
+            while let Some((_, last, slot)) = bodies.next_if(|(first, _, _)| *first == pc) {
+                loops.push((slot, open.last().map(|(l, _)| *l)));
+                open.push((loops.len() - 1, last));
+            }
+            inner.push(open.last().map(|(l, _)| *l));
+            offsets.push(n_states);
+            safe_add(&mut n_states, &(open.len() + 1), || EvalError::PCOverFlow)?;
+        }
+        offsets.push(n_states);
+
+        Ok(Loops {
+            inner,
+            loops,
+            offsets,
+        })
+    }
+
+    /// 状態の数を返す
+    fn n_states(&self) -> usize {
+        self.offsets.last().copied().unwrap_or(0)
+    }
+
+    /// pcとspとスロットの値から、状態の番号を返す
+    fn state(&self, pc: usize, sp: usize, slots: &Slots) -> Result<usize, EvalError> {
+        let mut n_started = 0;
+        let mut l = *self.inner.get(pc).ok_or(EvalError::InvalidPC)?;
+        while let Some((slot, outer)) = l.and_then(|l| self.loops.get(l)) {
+            if *slots.get(*slot).ok_or(EvalError::InvalidSlot)? == Some(sp) {
+                n_started += 1;
+            }
+            l = *outer;
+        }
+        Ok(self.offsets[pc] + n_started)
+    }
+}
+
+/// 深さ優先探索で訪れた(pc, sp)を記録するビット集合
+/// 後方参照や部分プログラムのない命令列でスロットの値を読むのはprogressだけで、
+/// その結果はpcを囲むループのうちspで繰り返しを始めたものの数で決まる(Loopsを参照)
+/// そのため(pc, sp)とその数が同じ状態から先の辿り方は、他のスロットの値によらず同じになる
+/// 一度訪れて戻ってきた状態はマッチしないことがわかっているので、二度目は辿らずに失敗とする
+/// 優先度の高い分岐から辿ることは変わらないので、leftmost-firstの結果は変わらない
+struct Visited {
+    bits: Vec<u64>,
+    loops: Loops,
+    start: usize, // 評価を始めた位置。これより前のspは訪れない
+    width: usize, // 1状態あたりのspの数
+}
+
+impl Visited {
+    /// loopsの状態とlineのstart文字目以降の位置についてのビット集合を作る
+    /// 大きさがbudgetバイトを超える場合はNoneを返す
+    fn new(loops: Loops, line: &[char], start: usize, budget: usize) -> Option<Visited> {
+        let width = line.len().checked_sub(start)?.checked_add(1)?;
+        let n_words = loops.n_states().checked_mul(width)?.div_ceil(64);
+        if n_words.checked_mul(mem::size_of::<u64>())? > budget {
+            return None;
+        }
+        Some(Visited {
+            bits: vec![0; n_words],
+            loops,
+            start,
+            width,
+        })
+    }
+
+    /// (pc, sp)を訪れたことを記録する。既に訪れていればfalseを返す
+    fn insert(&mut self, pc: usize, sp: usize, slots: &Slots) -> Result<bool, EvalError> {
+        let i = sp
+            .checked_sub(self.start)
+            .filter(|i| *i < self.width)
+            .ok_or(EvalError::SPOverFlow)?;
+        let i = self
+            .loops
+            .state(pc, sp, slots)?
+            .checked_mul(self.width)
+            .and_then(|p| p.checked_add(i))
+            .ok_or(EvalError::PCOverFlow)?;
+        let (word, bit) = (i / 64, 1 << (i % 64));
+        let w = self.bits.get_mut(word).ok_or(EvalError::InvalidPC)?;
+        let is_new = *w & bit == 0;
+        *w |= bit;
+        Ok(is_new)
+    }
+}
+
+/// 深さ優先探索で後戻りするためにスタックに積む情報
+#[derive(Debug)]
+enum Backtrack {
+    Branch { pc: usize, sp: usize }, // Splitで後回しにした分岐
+    Restore { slot: usize, old: Option<usize> }, // 書き換える前のスロットの値
+}
+
+/// 深さ優先探索の後戻りのためのスタック
+/// 分岐のたびに再帰呼び出しをすると長い入力でRustのスタックが溢れるので、ヒープ上に積む
+struct BacktrackStack {
+    stack: Vec<Backtrack>,
+    limit: usize, // 積める情報の数の上限
+}
+
+impl BacktrackStack {
+    fn new(limit: usize) -> BacktrackStack {
+        BacktrackStack {
+            stack: Vec::new(),
+            limit,
+        }
+    }
+
+    /// 情報を積む。上限を超える場合はEvalError::StackLimitExceededを返す
+    fn push(&mut self, b: Backtrack) -> Result<(), EvalError> {
+        if self.stack.len() >= self.limit {
+            return Err(EvalError::StackLimitExceeded(self.limit));
+        }
+        self.stack.push(b);
+        Ok(())
+    }
+
+    /// slotをvalueに書き換え、後戻りしたときに元に戻せるよう書き換える前の値を積む
+    fn save(
+        &mut self,
+        slots: &mut Slots,
+        slot: usize,
+        value: Option<usize>,
+    ) -> Result<(), EvalError> {
+        let old = *slots.get(slot).ok_or(EvalError::InvalidSlot)?;
+        if old != value {
+            self.push(Backtrack::Restore { slot, old })?;
+            slots[slot] = value;
+        }
+        Ok(())
+    }
+
+    /// 部分プログラムが書き換えたスロットをslotsに反映する
+    fn save_all(&mut self, slots: &mut Slots, new_slots: Slots) -> Result<(), EvalError> {
+        for (slot, value) in new_slots.into_iter().enumerate() {
+            self.save(slots, slot, value)?;
+        }
+        Ok(())
+    }
+
+    /// 後回しにした分岐まで戻り、その間に書き換えたスロットを元に戻す
+    /// 分岐が残っていなければNoneを返す
+    fn backtrack(&mut self, slots: &mut Slots) -> Option<(usize, usize)> {
+        while let Some(b) = self.stack.pop() {
+            match b {
+                Backtrack::Branch { pc, sp } => return Some((pc, sp)),
+                Backtrack::Restore { slot, old } => slots[slot] = old,
+            }
+        }
+        None
+    }
+}
+
+/// 深さ優先探索で評価する
+/// visitedがあれば訪れた(pc, sp)を記録し、同じ状態を二度辿らない
 fn eval_depth(
     inst: &[Instruction],
     line: &[char],
     mut pc: usize,
     mut sp: usize,
-    backward_match: bool,
+    slots: &mut Slots,
+    frame: Frame,
+    visited: &mut Option<Visited>,
 ) -> Result<bool, EvalError> {
-    // 文字数。Match到達の際、backward_matchが有効だったらばspがlast_indexと一致しているはず
-    let last_index = line.len();
+    let mut stack = BacktrackStack::new(frame.limits.stack);
+
     loop {
+        if frame.end == Some(pc) {
+            // 呼び出したグループの終わりに来たので、その位置を保存して呼び出し元に戻る
+            save(slots, 1, sp)?;
+            return Ok(true);
+        }
+
         let next = if let Some(i) = inst.get(pc) {
             i
         } else {
             return Err(EvalError::InvalidPC);
         };
 
-        println!("inst: {:?}", inst);
-        println!("line: {:?}", line);
+        // 訪れたことのある状態からはマッチしないので、辿らずに後戻りする
+        let is_visited = match visited {
+            Some(v) => !v.insert(pc, sp, slots)?,
+            None => false,
+        };
 
-        match next {
-            Instruction::Char(c) => {
-                if let Some(sp_c) = line.get(sp) {
-                    if c == sp_c {
+        // 現在の分岐を続けられるならtrue
+        let is_alive = !is_visited
+            && match next {
+                Instruction::Char(c) => match read_char(line, sp, frame.reverse) {
+                    Some(sp_c) if c == sp_c => {
+                        safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
+                        step(&mut sp, frame.reverse)?;
+                        true
+                    }
+                    _ => false,
+                },
+                Instruction::Class(ranges) => match read_char(line, sp, frame.reverse) {
+                    Some(sp_c) if class::contains(ranges, *sp_c) => {
+                        safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
+                        step(&mut sp, frame.reverse)?;
+                        true
+                    }
+                    _ => false,
+                },
+                Instruction::CharFold(c) => match read_char(line, sp, frame.reverse) {
+                    Some(sp_c) if class::simple_fold(*sp_c) == *c => {
+                        safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
+                        step(&mut sp, frame.reverse)?;
+                        true
+                    }
+                    _ => false,
+                },
+                Instruction::Dot => match read_char(line, sp, frame.reverse) {
+                    // dotのときは、改行以外の文字があれば良いので、pcとspをインクリメント
+                    Some(sp_c) if *sp_c != '\n' => {
                         safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
-                        safe_add(&mut sp, &1, || EvalError::SPOverFlow)?;
+                        step(&mut sp, frame.reverse)?;
+                        true
+                    }
+                    _ => false,
+                },
+                Instruction::AnyChar => {
+                    if read_char(line, sp, frame.reverse).is_some() {
+                        safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
+                        step(&mut sp, frame.reverse)?;
+                        true
                     } else {
-                        return Ok(false);
+                        false
+                    }
+                }
+                Instruction::Save(slot) => {
+                    // 後戻りしたときに元に戻せるよう、書き換える前の値を積んでおく
+                    stack.save(slots, *slot, Some(sp))?;
+                    safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
+                    true
+                }
+                Instruction::Assert(assertion) => {
+                    if is_asserted(*assertion, line, sp) {
+                        safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
+                        true
+                    } else {
+                        false
+                    }
+                }
+                Instruction::Progress(slot, addr) => {
+                    // 繰り返しの開始位置から進んでいなければ、それ以上繰り返さずにループを抜ける
+                    if *slots.get(*slot).ok_or(EvalError::InvalidSlot)? != Some(sp) {
+                        safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
+                    } else {
+                        pc = *addr;
+                    }
+                    true
+                }
+                Instruction::LookAhead { negated, next } => {
+                    // 部分プログラムは次の命令から始まり、Matchで終わる
+                    let mut sub_pc = pc;
+                    safe_add(&mut sub_pc, &1, || EvalError::PCOverFlow)?;
+                    let mut sub_slots = slots.clone();
+                    let is_matched = eval_depth(
+                        inst,
+                        line,
+                        sub_pc,
+                        sp,
+                        &mut sub_slots,
+                        frame.sub_program(false)?,
+                        &mut None,
+                    )?;
+                    if is_matched != *negated {
+                        // 肯定先読みの中でキャプチャした位置は残す
+                        // 部分プログラムのMatchで書き換わったマッチの末尾は、最後のMatchで上書きされる
+                        if is_matched {
+                            stack.save_all(slots, sub_slots)?;
+                        }
+                        pc = *next;
+                    }
+                    is_matched != *negated
+                }
+                Instruction::LookBehind { negated, next } => {
+                    // 部分プログラムは逆順に生成されているので、現在の位置から後ろ向きに評価する
+                    let mut sub_pc = pc;
+                    safe_add(&mut sub_pc, &1, || EvalError::PCOverFlow)?;
+                    let mut sub_slots = slots.clone();
+                    let is_matched = eval_depth(
+                        inst,
+                        line,
+                        sub_pc,
+                        sp,
+                        &mut sub_slots,
+                        frame.sub_program(true)?,
+                        &mut None,
+                    )?;
+                    if is_matched != *negated {
+                        if is_matched {
+                            stack.save_all(slots, sub_slots)?;
+                        }
+                        pc = *next;
+                    }
+                    is_matched != *negated
+                }
+                Instruction::Atomic { next } => {
+                    // 部分プログラムの中で最初に成功した分岐だけを採用し、他の分岐には戻らない
+                    let mut sub_pc = pc;
+                    safe_add(&mut sub_pc, &1, || EvalError::PCOverFlow)?;
+                    let mut sub_slots = slots.clone();
+                    let is_matched = eval_depth(
+                        inst,
+                        line,
+                        sub_pc,
+                        sp,
+                        &mut sub_slots,
+                        frame.sub_program(frame.reverse)?,
+                        &mut None,
+                    )?;
+                    if is_matched {
+                        // 部分プログラムのMatchがスロット1に保存した位置から続ける
+                        sp = sub_slots[1].ok_or(EvalError::InvalidSlot)?;
+                        stack.save_all(slots, sub_slots)?;
+                        pc = *next;
+                    }
+                    is_matched
+                }
+                Instruction::Call { start, end, .. } => {
+                    // 呼び出したグループを、その終わりまでアトミックグループと同様に評価する
+                    let call_frame = frame.enter(false, Some(*end))?;
+                    let mut sub_slots = slots.clone();
+                    let is_matched = eval_depth(
+                        inst,
+                        line,
+                        *start,
+                        sp,
+                        &mut sub_slots,
+                        call_frame,
+                        &mut None,
+                    )?;
+                    if is_matched {
+                        // 呼び出しの中でキャプチャした位置は捨て、終わりの位置だけを使う
+                        sp = sub_slots[1].ok_or(EvalError::InvalidSlot)?;
+                        safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
+                    }
+                    is_matched
+                }
+                Instruction::Backref(group) | Instruction::BackrefFold(group) => {
+                    let fold = matches!(next, Instruction::BackrefFold(_));
+                    match match_backref(line, slots, *group, sp, frame.reverse, fold)? {
+                        Some(next_sp) => {
+                            safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
+                            sp = next_sp;
+                            true
+                        }
+                        None => false,
                     }
-                } else {
-                    return Ok(false);
                 }
+                Instruction::Match => {
+                    // マッチした末尾の位置を保存する
+                    save(slots, 1, sp)?;
+                    return Ok(true);
+                }
+                Instruction::Jump(addr) => {
+                    // jumpでは入力の値でpcを更新する
+                    pc = *addr;
+                    true
+                }
+                Instruction::CondJump(group, addr) => {
+                    // グループの開始位置と終了位置が両方保存されていれば、キャプチャしている
+                    let start = group.checked_mul(2).ok_or(EvalError::InvalidSlot)?;
+                    match (slots.get(start), slots.get(start + 1)) {
+                        (Some(Some(_)), Some(Some(_))) => {
+                            safe_add(&mut pc, &1, || EvalError::PCOverFlow)?
+                        }
+                        (Some(_), Some(_)) => pc = *addr,
+                        _ => return Err(EvalError::InvalidSlot),
+                    }
+                    true
+                }
+                Instruction::Split(addr1, addr2) => {
+                    // addr1側を優先して評価し、マッチしなかったらaddr2側を評価する
+                    // addr2側は後回しにしてスタックに積む
+                    stack.push(Backtrack::Branch { pc: *addr2, sp })?;
+                    pc = *addr1;
+                    true
+                }
+            };
+
+        if !is_alive {
+            // 後回しにした分岐に戻る。残っていなければマッチしない
+            match stack.backtrack(slots) {
+                Some((next_pc, next_sp)) => {
+                    pc = next_pc;
+                    sp = next_sp;
+                }
+                None => return Ok(false),
             }
-            Instruction::Dot => {
-                // dotのときは、対象の文字があれば良いので、文字があるならpcとspをインクリメント
-                safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
-                safe_add(&mut sp, &1, || EvalError::SPOverFlow)?;
+        }
+    }
+}
+
+/// 幅優先探索の1ステップの間にスレッドのリストに追加した状態の集合
+struct Added {
+    states: Vec<bool>,
+    loops: Loops,
+}
+
+impl Added {
+    fn new(loops: Loops) -> Added {
+        Added {
+            states: vec![false; loops.n_states()],
+            loops,
+        }
+    }
+
+    /// 状態を追加したことを記録する。既に追加していればfalseを返す
+    fn insert(&mut self, pc: usize, sp: usize, slots: &Slots) -> Result<bool, EvalError> {
+        let state = self.loops.state(pc, sp, slots)?;
+        let s = self.states.get_mut(state).ok_or(EvalError::InvalidPC)?;
+        Ok(!mem::replace(s, true))
+    }
+
+    fn clear(&mut self) {
+        self.states.iter_mut().for_each(|s| *s = false);
+    }
+}
+
+/// 幅優先探索のスレッド
+struct Thread {
+    pc: usize,
+    slots: Slots,
+}
+
+/// スレッドのリストにpcを追加する
+/// Jump、Split、Save、Assert、Progressは文字を消費しないので、ここで辿れるところまで辿ってから追加する
+/// visitedで同じステップ内で同じ状態を二重に追加しないようにしているため、
+/// スレッド数は状態の数を超えない
+fn add_thread(
+    inst: &[Instruction],
+    line: &[char],
+    list: &mut Vec<Thread>,
+    visited: &mut Added,
+    pc: usize,
+    sp: usize,
+    mut slots: Slots,
+) -> Result<(), EvalError> {
+    if !visited.insert(pc, sp, &slots)? {
+        return Ok(());
+    }
+
+    let mut next_pc = pc;
+    safe_add(&mut next_pc, &1, || EvalError::PCOverFlow)?;
+
+    match inst[pc] {
+        Instruction::Jump(addr) => add_thread(inst, line, list, visited, addr, sp, slots),
+        Instruction::Split(addr1, addr2) => {
+            // 優先度の高いaddr1側のスレッドを先に追加する
+            add_thread(inst, line, list, visited, addr1, sp, slots.clone())?;
+            add_thread(inst, line, list, visited, addr2, sp, slots)
+        }
+        Instruction::Save(slot) => {
+            save(&mut slots, slot, sp)?;
+            add_thread(inst, line, list, visited, next_pc, sp, slots)
+        }
+        Instruction::Assert(assertion) => {
+            // 条件を満たさないスレッドはここで捨てる
+            if is_asserted(assertion, line, sp) {
+                add_thread(inst, line, list, visited, next_pc, sp, slots)
+            } else {
+                Ok(())
             }
-            Instruction::Match => {
-                if last_index != sp && backward_match {
-                    return Ok(false);
-                }
-                return Ok(true);
+        }
+        Instruction::Progress(slot, addr) => {
+            // 文字を消費せずに終わった繰り返しのスレッドは、ループを抜ける
+            if *slots.get(slot).ok_or(EvalError::InvalidSlot)? != Some(sp) {
+                add_thread(inst, line, list, visited, next_pc, sp, slots)
+            } else {
+                add_thread(inst, line, list, visited, addr, sp, slots)
             }
-            Instruction::Jump(addr) => {
-                // jumpでは入力の値でpcを更新する
-                pc = *addr;
+        }
+        ref i => {
+            if let Some(feature) = unsupported_by_width(i) {
+                return Err(EvalError::Unsupported(feature));
             }
-            Instruction::Split(addr1, addr2) => {
-                if eval_depth(inst, line, *addr1, sp, backward_match)?
-                    || eval_depth(inst, line, *addr2, sp, backward_match)?
-                {
-                    return Ok(true);
-                } else {
-                    return Ok(false);
+            list.push(Thread { pc, slots });
+            Ok(())
+        }
+    }
+}
+
+/// 幅優先探索(Pike VM)で、lineのstart文字目以降で最も左にあるマッチを探す
+/// 入力を1文字ずつ読み進めながら、その位置で生きているスレッドをすべて同時に進める
+/// スレッド数は状態の数で抑えられるので、計算量はO(状態の数 × 文字数)となる
+/// 状態の数は、命令ごとの(それを囲む空文字列にマッチしうるループの数 + 1)の和
+///
+/// スレッドのリストは優先度順に並んでいるので、Matchに到達したスレッドより後ろのスレッドは捨てる
+/// こうすることで深さ優先探索と同じく、優先度の最も高いマッチ(leftmost-first)が得られる
+///
+/// マッチが見つかるまでは、各位置でその位置から始まるスレッドを最も低い優先度で追加する
+/// 一度の走査ですべての開始位置を試すので、開始位置ごとに評価し直す必要はない
+/// anchoredが真ならstart文字目からのみマッチを試みる
+fn eval_width(
+    inst: &[Instruction],
+    line: &[char],
+    start: usize,
+    n_slots: usize,
+    anchored: bool,
+) -> Result<Option<Slots>, EvalError> {
+    let mut visited = Added::new(Loops::new(inst)?);
+    let mut clist = Vec::new(); // 現在の位置のスレッド
+    let mut nlist = Vec::new(); // 次の位置のスレッド
+    let mut matched = None;
+    let mut sp = start;
+
+    loop {
+        // 左の位置から始まるマッチを優先するので、新しいスレッドは最後に追加する
+        // visitedには現在の位置のスレッドの状態が記録されている
+        if matched.is_none() && (!anchored || sp == start) && sp <= line.len() {
+            let mut slots = vec![None; n_slots.max(2)];
+            save(&mut slots, 0, sp)?;
+            add_thread(inst, line, &mut clist, &mut visited, 0, sp, slots)?;
+        }
+
+        // 生きているスレッドがなく、これ以上新しいスレッドも追加しなければ終わり
+        if clist.is_empty() && (matched.is_some() || anchored || sp >= line.len()) {
+            break;
+        }
+
+        visited.clear();
+
+        // 次の位置のスレッドを追加する際のsp
+        let mut next_sp = sp;
+        safe_add(&mut next_sp, &1, || EvalError::SPOverFlow)?;
+
+        for Thread { pc, mut slots } in clist.drain(..) {
+            let mut next_pc = pc;
+            safe_add(&mut next_pc, &1, || EvalError::PCOverFlow)?;
+
+            let is_next = match &inst[pc] {
+                Instruction::Char(c) => line.get(sp) == Some(c),
+                Instruction::Class(ranges) => {
+                    matches!(line.get(sp), Some(c) if class::contains(ranges, *c))
+                }
+                Instruction::CharFold(c) => {
+                    matches!(line.get(sp), Some(sp_c) if class::simple_fold(*sp_c) == *c)
+                }
+                Instruction::Dot => matches!(line.get(sp), Some(c) if *c != '\n'),
+                Instruction::AnyChar => line.get(sp).is_some(),
+                Instruction::Match => {
+                    // 優先度の低いスレッドは捨てる
+                    save(&mut slots, 1, sp)?;
+                    matched = Some(slots);
+                    break;
                 }
+                // Jump, Split, Save, Assert, Progressはadd_threadで辿り終えているので、ここには来ない
+                Instruction::Jump(_)
+                | Instruction::Split(_, _)
+                | Instruction::Save(_)
+                | Instruction::Assert(_)
+                | Instruction::Progress(_, _)
+                | Instruction::LookAhead { .. }
+                | Instruction::LookBehind { .. }
+                | Instruction::Backref(_)
+                | Instruction::BackrefFold(_)
+                | Instruction::Atomic { .. }
+                | Instruction::CondJump(_, _)
+                | Instruction::Call { .. } => return Err(EvalError::InvalidPC),
+            };
+
+            if is_next {
+                add_thread(
+                    inst,
+                    line,
+                    &mut nlist,
+                    &mut visited,
+                    next_pc,
+                    next_sp,
+                    slots,
+                )?;
             }
         }
+
+        clist.clear();
+        mem::swap(&mut clist, &mut nlist);
+        sp = next_sp;
+    }
+
+    Ok(matched)
+}
+
+/// 深さ優先探索で、lineのsp文字目から始まるマッチを探す
+/// visitedがあれば訪れた(pc, sp)を記録し、同じ状態を二度辿らない
+fn eval_depth_at(
+    inst: &[Instruction],
+    line: &[char],
+    sp: usize,
+    n_slots: usize,
+    limits: Limits,
+    visited: &mut Option<Visited>,
+) -> Result<Option<Slots>, EvalError> {
+    let mut slots = vec![None; n_slots.max(2)];
+    save(&mut slots, 0, sp)?;
+
+    let frame = Frame {
+        reverse: false,
+        end: None,
+        depth: 0,
+        limits,
+    };
+    if eval_depth(inst, line, 0, sp, &mut slots, frame, visited)? {
+        Ok(Some(slots))
+    } else {
+        Ok(None)
     }
 }
 
-/// Instructionの配列を受けて、line(入力文字列)にmatchしたらtrue、しなければfalse、例外時はEvalErrorを返す
-/// is_depthは有効の時深さ優先探索、無効の時幅優先探索(実装はTODO)を行う
+/// Instructionの配列を受けて、line(入力文字列)のstart文字目以降で最も左にあるマッチを探し、
+/// マッチしたらキャプチャのスロット、しなければNone、例外時はEvalErrorを返す
+/// スロットの数はn_slotsで、スロット0と1にはマッチ全体の開始位置と終了位置が入る
+/// is_depthは有効の時深さ優先探索、無効の時幅優先探索を行う
+/// anchoredが真ならstart文字目からのみマッチを試みる
+/// limitsは深さ優先探索で使う資源の上限
 pub fn eval(
     inst: &[Instruction],
     line: &[char],
+    start: usize,
+    n_slots: usize,
     is_depth: bool,
-    backward_match: bool,
-) -> Result<bool, EvalError> {
-    if is_depth {
-        eval_depth(inst, line, 0, 0, backward_match)
-    } else {
-        Ok(false)
-        // TODO
-        // eval_width();
+    anchored: bool,
+    limits: Limits,
+) -> Result<Option<Slots>, EvalError> {
+    // 幅優先探索は一度の走査ですべての開始位置を試す
+    if !is_depth {
+        return eval_width(inst, line, start, n_slots, anchored);
+    }
+
+    // 幅優先探索で評価できる命令列なら、状態から先の結果はキャプチャのスロットの値によらないので記録できる
+    // 失敗した状態は開始位置を変えても失敗するので、一度の探索の間は同じビット集合を使い回す
+    // ビット集合が上限を超える場合は、同じくO(状態の数 × 文字数)で済む幅優先探索で評価する
+    let mut visited = None;
+    if check_width(inst).is_ok() {
+        match Visited::new(Loops::new(inst)?, line, start, limits.memo_budget) {
+            Some(v) => visited = Some(v),
+            None => return eval_width(inst, line, start, n_slots, anchored),
+        }
+    }
+
+    // 深さ優先探索は、左の開始位置から順にマッチを試みる
+    let last_start = if anchored { start } else { line.len() };
+    for sp in start..=last_start {
+        if let Some(slots) = eval_depth_at(inst, line, sp, n_slots, limits, &mut visited)? {
+            return Ok(Some(slots));
+        }
     }
+    Ok(None)
 }
@@ -1,38 +1,110 @@
+use super::{class, Assertion};
 use std::{
     error::Error,
     fmt::{self, Display},
+    iter::{Enumerate, Peekable},
     // 入力の変数から、所有権の取得しその変数の初期化を同時に行う
-    mem::take,
+    mem::{self, take},
+    str::Chars,
 };
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug)]
 pub enum AST {
     Char(char),
-    // 以下の4つは対象となるASTを受ける
+    // 以下の7つは対象となるASTを受ける
     Plus(Box<AST>),         // 正規表現の+
     Star(Box<AST>),         // 正規表現の*
     Question(Box<AST>),     // 正規表現の?
     Or(Box<AST>, Box<AST>), // 正規表現の|
-    Dot,                    // 正規表現の. 任意の位置文字
+    PlusLazy(Box<AST>),     // 正規表現の+?
+    StarLazy(Box<AST>),     // 正規表現の*?
+    QuestionLazy(Box<AST>), // 正規表現の??
+    Dot,                    // 正規表現の. 改行以外の任意の1文字
+    AnyChar,                // (?s)での正規表現の. 改行も含む任意の1文字
+    CharFold(char),         // (?i)での文字。大文字小文字を区別しない
+    // 正規表現の{n}, {n,}, {n,m}。maxがNoneなら上限なし。greedyが偽なら{n,m}?
+    Repeat {
+        ast: Box<AST>,
+        min: usize,
+        max: Option<usize>,
+        greedy: bool,
+    },
+    // 正規表現の[...]。rangesは正規化済みの文字の範囲で、negatedが真なら[^...]
+    // case_insensitiveが真なら大文字小文字を区別しない
+    Class {
+        ranges: Vec<(char, char)>,
+        negated: bool,
+        case_insensitive: bool,
+    },
+    // 正規表現の(...)と(?P<name>...)。usizeは1から始まるグループの番号
+    // グループの名前はAstState::namesに番号と対応付けて保持する
+    Capture(usize, Box<AST>),
+    // \A \z \b \Bのような、文字を消費しない位置の条件
+    Assert(Assertion),
+    // 正規表現の(?=...)。negatedが真なら(?!...)。文字を消費せずにastがマッチするか調べる
+    LookAhead {
+        ast: Box<AST>,
+        negated: bool,
+    },
+    // 正規表現の(?<=...)。negatedが真なら(?<!...)。現在の位置で終わる文字列にastがマッチするか調べる
+    LookBehind {
+        ast: Box<AST>,
+        negated: bool,
+    },
+    // 正規表現の(?>...)と、*+ ++ ?+ {n,m}+の強欲な量指定子
+    // 中の式が一度マッチしたら、その中の別の分岐は試さない
+    Atomic(Box<AST>),
+    // 正規表現の(?(1)yes|no)。groupのグループがキャプチャしていればyes、そうでなければnoにマッチする
+    // noがNoneなら空文字列にマッチする
+    Conditional {
+        group: usize,
+        yes: Box<AST>,
+        no: Option<Box<AST>>,
+    },
+    // 正規表現の(?R)や(?1)、(?&name)。usizeのグループ(0ならパターン全体)を再帰的に呼び出す
+    Call(usize),
+    // 正規表現の\1や\k<name>。groupのグループがキャプチャした文字列にマッチする
+    // case_insensitiveが真なら大文字小文字を区別しない
+    Backref {
+        group: usize,
+        case_insensitive: bool,
+    },
     // 複数のASTをまとめて扱うために使う
     Seq(Vec<AST>),
 }
 
 pub struct AstState {
     pub ast: AST,
-    pub has_hat: bool,
-    pub has_dollar: bool,
+    pub captures: usize,            // キャプチャグループの数
+    pub names: Vec<Option<String>>, // グループ番号ごとの名前。0番目はマッチ全体なので常にNone
 }
 
 #[derive(Debug)]
 pub enum ParseError {
-    InvalidEscape(usize, char), // 誤ったエスケープシーケンス
-    InvalidRightParen(usize),   // 開きカッコなし
-    NoPrev(usize),              // + | * ? の前に式がない
-    NoRightParen,               // 閉じカッコなし
-    InvalidHat,                 // ^が先頭以外にある
-    InvalidDollar,              // $が末尾以外にある
-    Empty,                      // 空のパターン
+    InvalidEscape(usize, char),        // 誤ったエスケープシーケンス
+    InvalidRightParen(usize),          // 開きカッコなし
+    NoPrev(usize),                     // + | * ? の前に式がない
+    NoRightParen,                      // 閉じカッコなし
+    NoRightBracket,                    // 文字クラスの閉じ角カッコなし
+    EmptyClass(usize),                 // []のような空の文字クラス
+    InvalidRange(usize, char, char),   // [z-a]のように範囲の始点が終点より大きい
+    InvalidRepeat(usize),              // {n,m}の形式が不正
+    UnknownGroup(usize, char),         // (?の後に未知の文字がある
+    InvalidGroupName(usize),           // (?P<name>のnameが不正
+    DuplicateGroupName(usize, String), // 同じ名前のグループが複数ある
+    IncompleteEscape(usize),           // エスケープシーケンスが途中で終わっている
+    InvalidHexDigit(usize, char),      // \xや\u{}の中に16進数でない文字がある
+    CodePointTooLarge(usize, u32),     // \x{}や\u{}の値が0x10FFFFを超える
+    SurrogateCodePoint(usize, u32),    // \x{}や\u{}の値がサロゲート領域にある
+    UnknownProperty(usize, String),    // \p{}の名前が未知の性質
+    UnknownPosixClass(usize, String),  // [[:name:]]のnameが未知のクラス
+    UnboundedLookBehind(usize),        // (?<=a*)のように後読みの長さに上限がない
+    InvalidBackref(usize, usize),      // \1などの番号のグループがその位置より前にない
+    UnknownGroupName(usize, String),   // \k<name>の名前のグループがその位置より前にない
+    InvalidCondition(usize),           // (?(1)...)の条件の形式が不正
+    TooManyBranches(usize),            // (?(1)a|b|c)のように条件分岐の選択肢が3つ以上ある
+    Empty,                             // 空のパターン
 }
 
 impl Display for ParseError {
@@ -50,8 +122,93 @@ impl Display for ParseError {
             ParseError::NoRightParen => {
                 write!(f, "ParseError: no right parenthesis")
             }
-            ParseError::InvalidHat => write!(f, "ParseEror: ^ is not at the beggining"),
-            ParseError::InvalidDollar => write!(f, "ParseEror: $ is not at end"),
+            ParseError::NoRightBracket => {
+                write!(f, "ParseError: no right bracket")
+            }
+            ParseError::EmptyClass(pos) => {
+                write!(f, "ParseError: empty character class: pos = {pos}")
+            }
+            ParseError::InvalidRange(pos, start, end) => {
+                write!(
+                    f,
+                    "ParseError: invalid range: pos = {pos}, range = '{start}-{end}'"
+                )
+            }
+            ParseError::InvalidRepeat(pos) => {
+                write!(f, "ParseError: invalid repetition: pos = {pos}")
+            }
+            ParseError::UnknownGroup(pos, c) => {
+                write!(
+                    f,
+                    "ParseError: unknown group flag: pos = {pos}, char = '{c}'"
+                )
+            }
+            ParseError::InvalidGroupName(pos) => {
+                write!(f, "ParseError: invalid group name: pos = {pos}")
+            }
+            ParseError::DuplicateGroupName(pos, name) => {
+                write!(
+                    f,
+                    "ParseError: duplicate group name: pos = {pos}, name = '{name}'"
+                )
+            }
+            ParseError::IncompleteEscape(pos) => {
+                write!(f, "ParseError: incomplete escape: pos = {pos}")
+            }
+            ParseError::InvalidHexDigit(pos, c) => {
+                write!(
+                    f,
+                    "ParseError: invalid hex digit: pos = {pos}, char = '{c}'"
+                )
+            }
+            ParseError::CodePointTooLarge(pos, n) => {
+                write!(
+                    f,
+                    "ParseError: code point too large: pos = {pos}, code point = {n:#X}"
+                )
+            }
+            ParseError::SurrogateCodePoint(pos, n) => {
+                write!(
+                    f,
+                    "ParseError: surrogate code point: pos = {pos}, code point = {n:#X}"
+                )
+            }
+            ParseError::UnknownProperty(pos, name) => {
+                write!(
+                    f,
+                    "ParseError: unknown Unicode property: pos = {pos}, name = {name}"
+                )
+            }
+            ParseError::UnknownPosixClass(pos, name) => {
+                write!(
+                    f,
+                    "ParseError: unknown POSIX class: pos = {pos}, name = {name}"
+                )
+            }
+            ParseError::UnboundedLookBehind(pos) => {
+                write!(f, "ParseError: unbounded lookbehind: pos = {pos}")
+            }
+            ParseError::InvalidBackref(pos, group) => {
+                write!(
+                    f,
+                    "ParseError: invalid backreference: pos = {pos}, group = {group}"
+                )
+            }
+            ParseError::UnknownGroupName(pos, name) => {
+                write!(
+                    f,
+                    "ParseError: unknown group name: pos = {pos}, name = '{name}'"
+                )
+            }
+            ParseError::InvalidCondition(pos) => {
+                write!(f, "ParseError: invalid condition: pos = {pos}")
+            }
+            ParseError::TooManyBranches(pos) => {
+                write!(
+                    f,
+                    "ParseError: too many branches in conditional: pos = {pos}"
+                )
+            }
             ParseError::Empty => write!(f, "ParseEror: empty expression"),
         }
     }
@@ -59,9 +216,199 @@ impl Display for ParseError {
 
 impl Error for ParseError {}
 
-fn parse_escape(pos: usize, c: char) -> Result<AST, ParseError> {
+/// 式の文字と位置を読み進めるイテレータ
+type ExprChars<'a> = Peekable<Enumerate<Chars<'a>>>;
+
+/// 解釈に影響するフラグ
+/// unicode以外は(?imsx)や(?imsx:...)で変更でき、その効果はグループの終わりまで続く
+#[derive(Debug, Clone, Copy, Default)]
+struct Flags {
+    case_insensitive: bool, // i 大文字小文字を区別しない
+    multi_line: bool,       // m ^と$が行の先頭と末尾にもマッチする
+    dot_all: bool,          // s .が改行にもマッチする
+    verbose: bool,          // x 空白と#から行末までのコメントを無視する
+    unicode: bool,          // \d \w \sをUnicodeの定義で解釈する
+}
+
+/// フラグに応じて1文字のASTを返す
+fn char_ast(c: char, flags: Flags) -> AST {
+    if flags.case_insensitive {
+        AST::CharFold(c)
+    } else {
+        AST::Char(c)
+    }
+}
+
+/// 16進数の数字を1文字読む
+fn parse_hex_digit(chars: &mut ExprChars, pos: usize) -> Result<u32, ParseError> {
+    match chars.next() {
+        Some((i, c)) => c.to_digit(16).ok_or(ParseError::InvalidHexDigit(i, c)),
+        None => Err(ParseError::IncompleteEscape(pos)),
+    }
+}
+
+/// \x{の{の直後から}までを読み、16進数の値を返す
+fn parse_hex_brace(chars: &mut ExprChars, pos: usize) -> Result<u32, ParseError> {
+    let mut n: u32 = 0;
+    let mut digits = 0;
+    loop {
+        match chars.peek() {
+            Some((_, '}')) if digits > 0 => {
+                chars.next();
+                return Ok(n);
+            }
+            Some(_) => {
+                let d = parse_hex_digit(chars, pos)?;
+                // 0x10FFFFを超えた時点で打ち切り、オーバーフローさせない
+                n = n.saturating_mul(16).saturating_add(d).min(0x110000);
+                digits += 1;
+            }
+            None => return Err(ParseError::IncompleteEscape(pos)),
+        }
+    }
+}
+
+/// \n \t \r \0 \xHH \x{HHHH} \u{HHHH}を解釈して文字を返す
+/// cがそのどれでもなければ何も読まずにNoneを返す
+/// posはcの位置
+fn parse_char_escape(
+    chars: &mut ExprChars,
+    pos: usize,
+    c: char,
+) -> Result<Option<char>, ParseError> {
+    let n = match c {
+        'n' => return Ok(Some('\n')),
+        't' => return Ok(Some('\t')),
+        'r' => return Ok(Some('\r')),
+        '0' => return Ok(Some('\0')),
+        'x' if chars.next_if(|(_, c)| *c == '{').is_none() => {
+            // \xHHは2桁固定
+            let h = parse_hex_digit(chars, pos)?;
+            let l = parse_hex_digit(chars, pos)?;
+            h * 16 + l
+        }
+        'x' => parse_hex_brace(chars, pos)?,
+        'u' => {
+            if chars.next_if(|(_, c)| *c == '{').is_none() {
+                return Err(ParseError::IncompleteEscape(pos));
+            }
+            parse_hex_brace(chars, pos)?
+        }
+        _ => return Ok(None),
+    };
+
+    if (0xD800..=0xDFFF).contains(&n) {
+        Err(ParseError::SurrogateCodePoint(pos, n))
+    } else {
+        char::from_u32(n)
+            .map(Some)
+            .ok_or(ParseError::CodePointTooLarge(pos, n))
+    }
+}
+
+/// Unicodeの性質の範囲の集合と、否定かどうか
+type Property = (&'static [(char, char)], bool);
+
+/// \pや\Pの直後から読み、Unicodeの性質の範囲の集合と否定かどうかを返す
+/// 名前は\pLのような1文字か、\p{Hiragana}のように{}で囲む
+/// posはcの位置
+fn parse_property(chars: &mut ExprChars, pos: usize, c: char) -> Result<Property, ParseError> {
+    let mut name = String::new();
+    match chars.next() {
+        Some((_, '{')) => loop {
+            match chars.next() {
+                Some((_, '}')) => break,
+                Some((_, c)) => name.push(c),
+                None => return Err(ParseError::IncompleteEscape(pos)),
+            }
+        },
+        Some((_, c)) => name.push(c),
+        None => return Err(ParseError::IncompleteEscape(pos)),
+    }
+
+    match class::unicode_property(&name) {
+        Some(ranges) => Ok((ranges, c == 'P')),
+        None => Err(ParseError::UnknownProperty(pos, name)),
+    }
+}
+
+/// \1から\9と\k<name>を解釈して後方参照のASTを返す
+/// 参照するグループは、参照の位置より前で開始している必要がある
+/// namesはここまでに開始したグループの名前、posはcの位置
+fn parse_backref(
+    chars: &mut ExprChars,
+    pos: usize,
+    c: char,
+    names: &[Option<String>],
+    flags: Flags,
+) -> Result<AST, ParseError> {
+    let group = if c == 'k' {
+        if chars.next_if(|(_, c)| *c == '<').is_none() {
+            return Err(ParseError::InvalidGroupName(pos));
+        }
+        let name = parse_group_name(chars, pos, '>')?;
+        names
+            .iter()
+            .position(|n| n.as_ref() == Some(&name))
+            .ok_or(ParseError::UnknownGroupName(pos, name))?
+    } else {
+        // cは1から9なのでto_digitは必ず成功する
+        let group = c.to_digit(10).unwrap() as usize;
+        if group >= names.len() {
+            return Err(ParseError::InvalidBackref(pos, group));
+        }
+        group
+    };
+
+    Ok(AST::Backref {
+        group,
+        case_insensitive: flags.case_insensitive,
+    })
+}
+
+/// \の直後の文字を解釈する
+fn parse_escape(
+    chars: &mut ExprChars,
+    pos: usize,
+    c: char,
+    flags: Flags,
+) -> Result<AST, ParseError> {
+    let unicode = flags.unicode;
+    if let Some((ranges, negated)) = class::perl_class(c, unicode) {
+        return Ok(AST::Class {
+            ranges: ranges.to_vec(),
+            negated,
+            case_insensitive: false,
+        });
+    }
+
+    if c == 'p' || c == 'P' {
+        let (ranges, negated) = parse_property(chars, pos, c)?;
+        return Ok(AST::Class {
+            ranges: ranges.to_vec(),
+            negated,
+            case_insensitive: flags.case_insensitive,
+        });
+    }
+
+    if let Some(c) = parse_char_escape(chars, pos, c)? {
+        return Ok(char_ast(c, flags));
+    }
+
+    let assertion = match c {
+        'A' => Some(Assertion::StartText),
+        'z' => Some(Assertion::EndText),
+        'b' => Some(Assertion::WordBoundary { unicode }),
+        'B' => Some(Assertion::NotWordBoundary { unicode }),
+        _ => None,
+    };
+    if let Some(assertion) = assertion {
+        return Ok(AST::Assert(assertion));
+    }
+
     match c {
-        '\\' | '(' | ')' | '|' | '+' | '*' | '?' | '.' | '^' | '$' => Ok(AST::Char(c)),
+        '\\' | '(' | ')' | '|' | '+' | '*' | '?' | '.' | '^' | '$' | '[' | ']' | '{' | '}'
+        | '#' | ' ' => Ok(char_ast(c, flags)),
         _ => {
             let err = ParseError::InvalidEscape(pos, c);
             Err(err)
@@ -69,23 +416,37 @@ fn parse_escape(pos: usize, c: char) -> Result<AST, ParseError> {
     }
 }
 
+#[allow(clippy::upper_case_acronyms)]
 enum PSQ {
     Plus,
     Star,
     Question,
+    Repeat(usize, Option<usize>),
 }
 
+/// greedyが偽なら、*? +? ?? {n,m}?のような非貪欲な量指定子として扱う
 fn parse_plus_star_question(
     seq: &mut Vec<AST>,
     ast_type: PSQ,
+    greedy: bool,
     pos: usize,
 ) -> Result<(), ParseError> {
-    // *?+は直前の要素が必要なのでケツから一つpop
+    // *?+{n,m}は直前の要素が必要なのでケツから一つpop
     if let Some(prev) = seq.pop() {
-        let ast = match ast_type {
-            PSQ::Plus => AST::Plus(Box::new(prev)),
-            PSQ::Star => AST::Star(Box::new(prev)),
-            PSQ::Question => AST::Question(Box::new(prev)),
+        let prev = Box::new(prev);
+        let ast = match (ast_type, greedy) {
+            (PSQ::Plus, true) => AST::Plus(prev),
+            (PSQ::Star, true) => AST::Star(prev),
+            (PSQ::Question, true) => AST::Question(prev),
+            (PSQ::Plus, false) => AST::PlusLazy(prev),
+            (PSQ::Star, false) => AST::StarLazy(prev),
+            (PSQ::Question, false) => AST::QuestionLazy(prev),
+            (PSQ::Repeat(min, max), greedy) => AST::Repeat {
+                ast: prev,
+                min,
+                max,
+                greedy,
+            },
         };
         // できたastをpush
         seq.push(ast);
@@ -95,6 +456,505 @@ fn parse_plus_star_question(
     }
 }
 
+/// 量指定子の直後に?があれば読み飛ばし、非貪欲であることを示すfalseを返す
+fn parse_greedy(chars: &mut ExprChars) -> bool {
+    chars.next_if(|(_, c)| *c == '?').is_none()
+}
+
+/// 貪欲な量指定子の直後に+があれば読み飛ばし、*+ ++ ?+ {n,m}+のような強欲な量指定子とする
+/// 強欲な量指定子は、直前に作った量指定子のASTをアトミックグループにしたものとして扱う
+fn parse_possessive(chars: &mut ExprChars, seq: &mut Vec<AST>, greedy: bool) {
+    if greedy && chars.next_if(|(_, c)| *c == '+').is_some() {
+        if let Some(prev) = seq.pop() {
+            seq.push(AST::Atomic(Box::new(prev)));
+        }
+    }
+}
+
+/// 10進数の数値を読む。数字がなければNoneを返す
+/// posはエラー時に報告する位置
+fn parse_number(chars: &mut ExprChars, pos: usize) -> Result<Option<usize>, ParseError> {
+    let mut n: Option<usize> = None;
+    while let Some((_, c)) = chars.next_if(|(_, c)| c.is_ascii_digit()) {
+        let d = c.to_digit(10).unwrap() as usize;
+        let m = n
+            .unwrap_or(0)
+            .checked_mul(10)
+            .and_then(|m| m.checked_add(d))
+            .ok_or(ParseError::InvalidRepeat(pos))?;
+        n = Some(m);
+    }
+    Ok(n)
+}
+
+/// {の直後から}までを読み、繰り返し回数の下限と上限を返す
+/// posは{の位置
+fn parse_repeat(chars: &mut ExprChars, pos: usize) -> Result<PSQ, ParseError> {
+    let min = parse_number(chars, pos)?.ok_or(ParseError::InvalidRepeat(pos))?;
+
+    let max = match chars.next() {
+        Some((_, '}')) => return Ok(PSQ::Repeat(min, Some(min))),
+        Some((_, ',')) => parse_number(chars, pos)?,
+        _ => return Err(ParseError::InvalidRepeat(pos)),
+    };
+
+    if !matches!(chars.next(), Some((_, '}'))) {
+        return Err(ParseError::InvalidRepeat(pos));
+    }
+    if matches!(max, Some(max) if max < min) {
+        return Err(ParseError::InvalidRepeat(pos));
+    }
+    Ok(PSQ::Repeat(min, max))
+}
+
+/// 文字クラス内のエスケープシーケンスを解釈する
+fn parse_class_escape(chars: &mut ExprChars, pos: usize, c: char) -> Result<char, ParseError> {
+    if let Some(c) = parse_char_escape(chars, pos, c)? {
+        return Ok(c);
+    }
+
+    match c {
+        '\\' | '(' | ')' | '|' | '+' | '*' | '?' | '.' | '^' | '$' | '[' | ']' | '-' | '&'
+        | '~' => Ok(c),
+        _ => Err(ParseError::InvalidEscape(pos, c)),
+    }
+}
+
+/// 文字クラス内の1文字を読む。エスケープされていればそれを解釈する
+fn parse_class_char(chars: &mut ExprChars, c: char) -> Result<char, ParseError> {
+    if c == '\\' {
+        let (i, c) = chars.next().ok_or(ParseError::NoRightBracket)?;
+        parse_class_escape(chars, i, c)
+    } else {
+        Ok(c)
+    }
+}
+
+/// 文字クラス内で否定した範囲の集合を返す。rangesは正規化されている必要がある
+/// 大文字小文字を区別しない場合は、畳み込んでから否定する
+/// 否定してから畳み込むと、(?i)[\P{Ll}]が否定したはずのaにもマッチしてしまう
+fn negate_class(ranges: &[(char, char)], flags: Flags) -> Vec<(char, char)> {
+    if flags.case_insensitive {
+        class::negate(&class::fold_ranges(ranges))
+    } else {
+        class::negate(ranges)
+    }
+}
+
+/// 文字クラス内の\d \w \sや\p{...}などを読み、その範囲をrangesに追加する
+/// 該当しなければ何も読まずにfalseを返す
+fn parse_class_perl(
+    chars: &mut ExprChars,
+    ranges: &mut Vec<(char, char)>,
+    flags: Flags,
+) -> Result<bool, ParseError> {
+    let mut ahead = chars.clone();
+    let perl = match (ahead.next(), ahead.next()) {
+        (Some((_, '\\')), Some((i, c @ ('p' | 'P')))) => Some(parse_property(&mut ahead, i, c)?),
+        (Some((_, '\\')), Some((_, c))) => class::perl_class(c, flags.unicode),
+        _ => None,
+    };
+
+    if let Some((perl_ranges, negated)) = perl {
+        if negated {
+            ranges.extend(negate_class(perl_ranges, flags));
+        } else {
+            ranges.extend_from_slice(perl_ranges);
+        }
+        *chars = ahead;
+        Ok(true)
+    } else {
+        Ok(false)
+    }
+}
+
+/// 文字クラス内の[:alpha:]や[:^alpha:]などのPOSIXクラスを読み、その範囲をrangesに追加する
+/// [:name:]の形になっていなければ何も読まずにfalseを返す
+fn parse_class_posix(
+    chars: &mut ExprChars,
+    ranges: &mut Vec<(char, char)>,
+    flags: Flags,
+) -> Result<bool, ParseError> {
+    let mut ahead = chars.clone();
+    let pos = match (ahead.next(), ahead.next()) {
+        (Some((i, '[')), Some((_, ':'))) => i,
+        _ => return Ok(false),
+    };
+    let negated = ahead.next_if(|(_, c)| *c == '^').is_some();
+
+    let mut name = String::new();
+    while let Some((_, c)) = ahead.next_if(|(_, c)| c.is_ascii_alphabetic()) {
+        name.push(c);
+    }
+    if !matches!(
+        (ahead.next(), ahead.next()),
+        (Some((_, ':')), Some((_, ']')))
+    ) {
+        return Ok(false);
+    }
+
+    let posix_ranges = class::posix_class(&name).ok_or(ParseError::UnknownPosixClass(pos, name))?;
+    if negated {
+        ranges.extend(negate_class(posix_ranges, flags));
+    } else {
+        ranges.extend_from_slice(posix_ranges);
+    }
+    *chars = ahead;
+    Ok(true)
+}
+
+/// 文字クラスの集合演算
+enum ClassOp {
+    Intersection,        // &&
+    Difference,          // --
+    SymmetricDifference, // ~~
+}
+
+/// 文字クラス内の&& -- ~~を読み、集合演算の種類を返す
+/// 該当しなければ何も読まずにNoneを返す
+fn parse_class_op(chars: &mut ExprChars) -> Option<ClassOp> {
+    let mut ahead = chars.clone();
+    let op = match (ahead.next(), ahead.next()) {
+        (Some((_, '&')), Some((_, '&'))) => ClassOp::Intersection,
+        (Some((_, '-')), Some((_, '-'))) => ClassOp::Difference,
+        (Some((_, '~')), Some((_, '~'))) => ClassOp::SymmetricDifference,
+        _ => return None,
+    };
+    *chars = ahead;
+    Some(op)
+}
+
+/// 集合演算の左辺lhsと右辺rhsを計算する。左辺がなければ右辺をそのまま返す
+/// 右辺が空の場合は[a&&]のように演算の対象がないのでエラーとする
+/// 大文字小文字を区別しない場合は、右辺を畳み込んでから計算する。左辺は畳み込み済みとなる
+fn apply_class_op(
+    lhs: Option<(Vec<(char, char)>, ClassOp)>,
+    rhs: Vec<(char, char)>,
+    pos: usize,
+    flags: Flags,
+) -> Result<Vec<(char, char)>, ParseError> {
+    if rhs.is_empty() {
+        return Err(ParseError::EmptyClass(pos));
+    }
+
+    let rhs = class::canonicalize(rhs);
+    let rhs = if flags.case_insensitive {
+        class::fold_ranges(&rhs)
+    } else {
+        rhs
+    };
+    Ok(match lhs {
+        None => rhs,
+        Some((lhs, ClassOp::Intersection)) => class::intersection(&lhs, &rhs),
+        Some((lhs, ClassOp::Difference)) => class::difference(&lhs, &rhs),
+        Some((lhs, ClassOp::SymmetricDifference)) => class::symmetric_difference(&lhs, &rhs),
+    })
+}
+
+/// [や[^の直後から]までを読み、正規化した範囲の集合を返す
+/// [a-z[0-9]]のように入れ子になった文字クラスは和集合とし、
+/// && -- ~~はそれぞれ積集合、差集合、対称差として左から順に計算する
+/// 大文字小文字を区別しない場合は、畳み込んだ集合を返す
+/// posは[の位置
+fn parse_class_set(
+    chars: &mut ExprChars,
+    pos: usize,
+    flags: Flags,
+) -> Result<Vec<(char, char)>, ParseError> {
+    let mut lhs = None; // 集合演算の左辺と演算の種類
+    let mut ranges = Vec::new();
+
+    loop {
+        if let Some(op) = parse_class_op(chars) {
+            let set = apply_class_op(lhs, take(&mut ranges), pos, flags)?;
+            lhs = Some((set, op));
+            continue;
+        }
+
+        // \dなどは範囲の始点や終点にはできないので、先に読んでしまう
+        if parse_class_perl(chars, &mut ranges, flags)?
+            || parse_class_posix(chars, &mut ranges, flags)?
+        {
+            continue;
+        }
+
+        // 入れ子の文字クラスは、否定も含めてここで範囲の集合にしてしまう
+        if let Some((i, _)) = chars.next_if(|(_, c)| *c == '[') {
+            let negated = chars.next_if(|(_, c)| *c == '^').is_some();
+            let set = parse_class_set(chars, i, flags)?;
+            if negated {
+                ranges.extend(negate_class(&set, flags));
+            } else {
+                ranges.extend(set);
+            }
+            continue;
+        }
+
+        let (i, c) = chars.next().ok_or(ParseError::NoRightBracket)?;
+        if c == ']' {
+            break;
+        }
+        let start = parse_class_char(chars, c)?;
+
+        // a-zのような範囲か調べる。-の直後が]なら-はただの文字として扱う
+        // --は差集合の演算子なので範囲にはしない
+        let mut ahead = chars.clone();
+        let is_range = matches!(ahead.next(), Some((_, '-')))
+            && matches!(ahead.next(), Some((_, c)) if c != ']' && c != '-');
+
+        if is_range {
+            chars.next(); // -を読み飛ばす
+            let (_, c) = chars.next().ok_or(ParseError::NoRightBracket)?;
+            let end = parse_class_char(chars, c)?;
+            if start > end {
+                return Err(ParseError::InvalidRange(i, start, end));
+            }
+            ranges.push((start, end));
+        } else {
+            ranges.push((start, start));
+        }
+    }
+
+    apply_class_op(lhs, ranges, pos, flags)
+}
+
+/// [の直後から]までを読み、文字クラスのASTを返す
+/// posは[の位置
+fn parse_class(chars: &mut ExprChars, pos: usize, flags: Flags) -> Result<AST, ParseError> {
+    // 先頭の^は否定
+    let negated = chars.next_if(|(_, c)| *c == '^').is_some();
+    let ranges = parse_class_set(chars, pos, flags)?;
+
+    Ok(AST::Class {
+        ranges,
+        negated,
+        case_insensitive: flags.case_insensitive,
+    })
+}
+
+/// (の種類
+enum Group {
+    Capture(usize),            // キャプチャグループ。usizeはグループの番号
+    NonCapture,                // (?:...)や(?i:...)
+    SetFlags,                  // (?i)のようにフラグの変更のみを行い、グループを作らない
+    LookAhead(bool),           // (?=...)。boolが真なら(?!...)
+    LookBehind(bool, usize),   // (?<=...)。boolが真なら(?<!...)。usizeは(の位置
+    Atomic,                    // (?>...)
+    Conditional(usize, usize), // (?(1)...)。usizeは条件のグループの番号と(の位置
+    Call(usize),               // (?R)や(?1)のようにグループを呼び出すのみで、グループを作らない
+}
+
+/// (?P<name>の<の直後からcloseまでを読み、グループ名を返す
+/// グループ名は英数字と_からなり、数字で始まってはいけない
+fn parse_group_name(chars: &mut ExprChars, pos: usize, close: char) -> Result<String, ParseError> {
+    let mut name = String::new();
+    loop {
+        match chars.next() {
+            Some((_, c)) if c == close => break,
+            Some((_, c)) if c.is_ascii_alphanumeric() || c == '_' => name.push(c),
+            _ => return Err(ParseError::InvalidGroupName(pos)),
+        }
+    }
+
+    match name.chars().next() {
+        Some(c) if !c.is_ascii_digit() => Ok(name),
+        _ => Err(ParseError::InvalidGroupName(pos)),
+    }
+}
+
+/// (?imsx-imsx)や(?imsx-imsx:の?の直後から読み、flagsを書き換える
+/// )で終わったらSetFlagsを、:で終わったらNonCaptureを返す
+fn parse_flags(chars: &mut ExprChars, flags: &mut Flags) -> Result<Group, ParseError> {
+    let mut enable = true; // -の後はフラグを無効にする
+    let mut is_empty = true;
+    loop {
+        let (i, c) = chars.next().ok_or(ParseError::NoRightParen)?;
+        match c {
+            'i' => flags.case_insensitive = enable,
+            'm' => flags.multi_line = enable,
+            's' => flags.dot_all = enable,
+            'x' => flags.verbose = enable,
+            '-' if enable => {
+                enable = false;
+                continue;
+            }
+            ')' if !is_empty => return Ok(Group::SetFlags),
+            ':' => return Ok(Group::NonCapture),
+            _ => return Err(ParseError::UnknownGroup(i, c)),
+        }
+        is_empty = false;
+    }
+}
+
+/// (?(の直後から)までを読み、条件のグループの番号を返す
+/// 条件は(?(1)のような番号か、(?(<name>)や(?(name)のような名前で、その位置より前で開始している必要がある
+/// posは(の位置
+fn parse_condition(
+    chars: &mut ExprChars,
+    pos: usize,
+    names: &[Option<String>],
+) -> Result<usize, ParseError> {
+    let mut cond = String::new();
+    loop {
+        match chars.next() {
+            Some((_, ')')) => break,
+            Some((_, c)) => cond.push(c),
+            None => return Err(ParseError::NoRightParen),
+        }
+    }
+
+    if let Ok(group) = cond.parse::<usize>() {
+        if group == 0 || group >= names.len() {
+            return Err(ParseError::InvalidBackref(pos, group));
+        }
+        return Ok(group);
+    }
+
+    let name = cond
+        .strip_prefix('<')
+        .and_then(|n| n.strip_suffix('>'))
+        .unwrap_or(&cond);
+    let is_valid = matches!(name.chars().next(), Some(c) if !c.is_ascii_digit())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !is_valid {
+        return Err(ParseError::InvalidCondition(pos));
+    }
+
+    names
+        .iter()
+        .position(|n| n.as_deref() == Some(name))
+        .ok_or_else(|| ParseError::UnknownGroupName(pos, name.to_string()))
+}
+
+/// (?R)、(?1)、(?&name)の?の直後から)までを読み、呼び出すグループの番号を返す
+/// 呼び出すグループは、その位置より前で開始している必要がある
+/// posは(の位置
+fn parse_call(
+    chars: &mut ExprChars,
+    pos: usize,
+    names: &[Option<String>],
+) -> Result<usize, ParseError> {
+    match chars.next() {
+        Some((_, 'R')) => match chars.next() {
+            Some((_, ')')) => Ok(0),
+            Some((i, c)) => Err(ParseError::UnknownGroup(i, c)),
+            None => Err(ParseError::NoRightParen),
+        },
+        Some((_, '&')) => {
+            let name = parse_group_name(chars, pos, ')')?;
+            names
+                .iter()
+                .position(|n| n.as_ref() == Some(&name))
+                .ok_or(ParseError::UnknownGroupName(pos, name))
+        }
+        Some((_, c)) => {
+            // cは数字なのでto_digitは必ず成功する
+            let mut group = c.to_digit(10).unwrap() as usize;
+            loop {
+                match chars.next() {
+                    Some((_, ')')) => break,
+                    Some((_, c)) if c.is_ascii_digit() => {
+                        group = group
+                            .checked_mul(10)
+                            .and_then(|g| g.checked_add(c.to_digit(10).unwrap() as usize))
+                            .ok_or(ParseError::InvalidBackref(pos, usize::MAX))?;
+                    }
+                    Some((i, c)) => return Err(ParseError::UnknownGroup(i, c)),
+                    None => return Err(ParseError::NoRightParen),
+                }
+            }
+            if group >= names.len() {
+                return Err(ParseError::InvalidBackref(pos, group));
+            }
+            Ok(group)
+        }
+        None => Err(ParseError::NoRightParen),
+    }
+}
+
+/// (の直後を読み、グループの種類を返す
+/// キャプチャグループの場合はnamesに名前を追加して番号を振る
+/// (?i)や(?i:...)の場合はflagsを書き換える
+/// posは(の位置
+fn parse_group(
+    chars: &mut ExprChars,
+    pos: usize,
+    names: &mut Vec<Option<String>>,
+    flags: &mut Flags,
+) -> Result<Group, ParseError> {
+    let name = if chars.next_if(|(_, c)| *c == '?').is_none() {
+        None
+    } else {
+        match chars.peek() {
+            Some((_, 'i' | 'm' | 's' | 'x' | '-')) => return parse_flags(chars, flags),
+            Some((_, 'R' | '&' | '0'..='9')) => {
+                return Ok(Group::Call(parse_call(chars, pos, names)?))
+            }
+            _ => (),
+        }
+
+        match chars.next() {
+            Some((_, ':')) => return Ok(Group::NonCapture),
+            Some((_, '>')) => return Ok(Group::Atomic),
+            Some((_, '(')) => {
+                return Ok(Group::Conditional(parse_condition(chars, pos, names)?, pos))
+            }
+            Some((_, '=')) => return Ok(Group::LookAhead(false)),
+            Some((_, '!')) => return Ok(Group::LookAhead(true)),
+            Some((_, '<')) => match chars.next_if(|(_, c)| *c == '=' || *c == '!') {
+                Some((_, c)) => return Ok(Group::LookBehind(c == '!', pos)),
+                None => Some(parse_group_name(chars, pos, '>')?),
+            },
+            Some((_, 'P')) => {
+                if chars.next_if(|(_, c)| *c == '<').is_none() {
+                    return Err(ParseError::InvalidGroupName(pos));
+                }
+                Some(parse_group_name(chars, pos, '>')?)
+            }
+            Some((i, c)) => return Err(ParseError::UnknownGroup(i, c)),
+            None => return Err(ParseError::NoRightParen),
+        }
+    };
+
+    if let Some(name) = &name {
+        if names.iter().flatten().any(|n| n == name) {
+            return Err(ParseError::DuplicateGroupName(pos, name.clone()));
+        }
+    }
+
+    names.push(name);
+    Ok(Group::Capture(names.len() - 1))
+}
+
+/// astがマッチする文字列の長さの最大値を返す。上限がなければNoneを返す
+fn max_len(ast: &AST) -> Option<usize> {
+    match ast {
+        AST::Char(_) | AST::CharFold(_) | AST::Dot | AST::AnyChar | AST::Class { .. } => Some(1),
+        AST::Assert(_) | AST::LookAhead { .. } | AST::LookBehind { .. } => Some(0),
+        // 後方参照はキャプチャした文字列の長さ次第なので、上限がないものとして扱う
+        // 呼び出しも、再帰的に呼び出されうるので上限がないものとして扱う
+        AST::Plus(_)
+        | AST::Star(_)
+        | AST::PlusLazy(_)
+        | AST::StarLazy(_)
+        | AST::Backref { .. }
+        | AST::Call(_) => None,
+        AST::Question(e) | AST::QuestionLazy(e) | AST::Capture(_, e) | AST::Atomic(e) => max_len(e),
+        AST::Conditional { yes, no, .. } => {
+            let no = match no {
+                Some(no) => max_len(no)?,
+                None => 0,
+            };
+            Some(max_len(yes)?.max(no))
+        }
+        AST::Or(e1, e2) => Some(max_len(e1)?.max(max_len(e2)?)),
+        AST::Repeat { ast, max, .. } => max_len(ast)?.checked_mul((*max)?),
+        AST::Seq(v) => v
+            .iter()
+            .try_fold(0usize, |len, e| len.checked_add(max_len(e)?)),
+    }
+}
+
 /// Orで結合された複数の式をASTにする
 fn fold_or(mut seq_or: Vec<AST>) -> Option<AST> {
     if seq_or.len() > 1 {
@@ -113,7 +973,9 @@ fn fold_or(mut seq_or: Vec<AST>) -> Option<AST> {
 }
 
 /// exprを解釈してASTを返す
-pub fn parse(expr: &str) -> Result<AstState, ParseError> {
+/// unicodeが偽なら\d \w \sはASCIIの範囲のみを対象にする
+/// case_insensitiveが真なら、(?i)から始まる式として解釈する
+pub fn parse(expr: &str, unicode: bool, case_insensitive: bool) -> Result<AstState, ParseError> {
     // 内部の状態を表現する。Charは文字列処理中。Escapeはエスケープシーケンス処理中
     enum ParseState {
         Char,
@@ -124,41 +986,128 @@ pub fn parse(expr: &str) -> Result<AstState, ParseError> {
     let mut seq_or = Vec::new(); // Orコンテキスト
     let mut stack = Vec::new(); // コンテキストのスタック
     let mut state = ParseState::Char;
-    let mut has_hat = false;
-    let mut has_dollar = false;
+    let mut names = vec![None]; // グループ番号ごとの名前。(が出現した順に番号を振る
+    let mut flags = Flags {
+        unicode,
+        case_insensitive,
+        ..Default::default()
+    };
+
+    let mut is_set_flags = false; // 直前が(?i)のようなフラグの変更なら真
 
-    for (i, c) in expr.chars().enumerate() {
+    let mut chars = expr.chars().enumerate().peekable();
+    while let Some((i, c)) = chars.next() {
+        // (?i)*のように、フラグの変更は量指定子の対象にならない
+        let after_set_flags = mem::replace(&mut is_set_flags, false);
         match &state {
             ParseState::Char => match c {
-                '+' => parse_plus_star_question(&mut seq, PSQ::Plus, i)?,
-                '*' => parse_plus_star_question(&mut seq, PSQ::Star, i)?,
-                '?' => parse_plus_star_question(&mut seq, PSQ::Question, i)?,
+                '+' | '*' | '?' | '{' if after_set_flags => return Err(ParseError::NoPrev(i)),
+                '+' | '*' | '?' => {
+                    let ast_type = match c {
+                        '+' => PSQ::Plus,
+                        '*' => PSQ::Star,
+                        _ => PSQ::Question,
+                    };
+                    let greedy = parse_greedy(&mut chars);
+                    parse_plus_star_question(&mut seq, ast_type, greedy, i)?;
+                    parse_possessive(&mut chars, &mut seq, greedy);
+                }
+                '{' => {
+                    let repeat = parse_repeat(&mut chars, i)?;
+                    let greedy = parse_greedy(&mut chars);
+                    parse_plus_star_question(&mut seq, repeat, greedy, i)?;
+                    parse_possessive(&mut chars, &mut seq, greedy);
+                }
                 // カッコでコンテキストを置き換えるところがちょっと複雑
                 '(' => {
+                    // グループの終わりで元に戻せるよう、変更前のフラグを保存する
+                    let prev_flags = flags;
+                    let group = parse_group(&mut chars, i, &mut names, &mut flags)?;
+                    // (?i)はフラグを変えるだけなので、コンテキストは置き換えない
+                    // (?R)や(?1)も呼び出しを追加するだけなので同様
+                    match group {
+                        Group::SetFlags => {
+                            is_set_flags = true;
+                            continue;
+                        }
+                        Group::Call(group) => {
+                            seq.push(AST::Call(group));
+                            continue;
+                        }
+                        _ => (),
+                    }
+
                     // 現在のコンテキストを保存しつつ、seqを空にする
                     let prev = take(&mut seq);
                     // 上に同じく
                     let prev_or = take(&mut seq_or);
-                    stack.push((prev, prev_or));
+                    stack.push((prev, prev_or, group, prev_flags));
                 }
                 ')' => {
                     // この時点でのseq及びseq_orは()の中を解釈した結果になっている
                     // コンテキストをスタックからpop
-                    if let Some((mut prev, prev_or)) = stack.pop() {
+                    if let Some((mut prev, prev_or, group, prev_flags)) = stack.pop() {
                         // ()のような評価対象がない場合はpushしない
-                        if !seq.is_empty() {
+                        // (a|)のように|の後が空の場合は、空文字列にマッチする選択肢として空のSeqをpushする
+                        if !seq.is_empty() || !seq_or.is_empty() {
                             seq_or.push(AST::Seq(seq));
                         }
 
                         // Orの生成
-                        if let Some(ast) = fold_or(seq_or) {
-                            // ここでprevにpushしているのは、prevが()を解釈する前の内容であるため
-                            prev.push(ast);
+                        let n_branches = seq_or.len(); // 条件分岐で選択肢の数を調べるために使う
+                        let ast = fold_or(seq_or);
+                        // ここでprevにpushしているのは、prevが()を解釈する前の内容であるため
+                        match group {
+                            Group::Capture(index) => {
+                                // ()のときも空文字列をキャプチャするので空のSeqとする
+                                let ast = ast.unwrap_or(AST::Seq(Vec::new()));
+                                prev.push(AST::Capture(index, Box::new(ast)));
+                            }
+                            // (?:)のときも後ろの量指定子の対象になるよう空のSeqとする
+                            // SetFlagsとCallはスタックに積まないので、ここには来ない
+                            Group::NonCapture | Group::SetFlags | Group::Call(_) => {
+                                prev.push(ast.unwrap_or(AST::Seq(Vec::new())))
+                            }
+                            Group::LookAhead(negated) => {
+                                // (?=)は常に成功し、(?!)は常に失敗する
+                                let ast = ast.unwrap_or(AST::Seq(Vec::new()));
+                                prev.push(AST::LookAhead {
+                                    ast: Box::new(ast),
+                                    negated,
+                                });
+                            }
+                            Group::Atomic => {
+                                let ast = ast.unwrap_or(AST::Seq(Vec::new()));
+                                prev.push(AST::Atomic(Box::new(ast)));
+                            }
+                            Group::Conditional(group, pos) => {
+                                // 選択肢が2つなら、fold_orでyes|noのOrになっている
+                                let (yes, no) = match ast {
+                                    _ if n_branches > 2 => {
+                                        return Err(ParseError::TooManyBranches(pos))
+                                    }
+                                    Some(AST::Or(yes, no)) if n_branches == 2 => (yes, Some(no)),
+                                    ast => (Box::new(ast.unwrap_or(AST::Seq(Vec::new()))), None),
+                                };
+                                prev.push(AST::Conditional { group, yes, no });
+                            }
+                            Group::LookBehind(negated, pos) => {
+                                let ast = ast.unwrap_or(AST::Seq(Vec::new()));
+                                // 後ろ向きに読む長さに上限がないと、どこまでも遡ってしまうのでエラーとする
+                                if max_len(&ast).is_none() {
+                                    return Err(ParseError::UnboundedLookBehind(pos));
+                                }
+                                prev.push(AST::LookBehind {
+                                    ast: Box::new(ast),
+                                    negated,
+                                });
+                            }
                         }
 
                         // 以前のコンテキストを現在のコンテキストに上書き
                         seq = prev;
                         seq_or = prev_or;
+                        flags = prev_flags;
                     } else {
                         return Err(ParseError::InvalidRightParen(i));
                     }
@@ -173,47 +1122,54 @@ pub fn parse(expr: &str) -> Result<AstState, ParseError> {
                     }
                 }
                 '\\' => state = ParseState::Escape,
+                '.' if flags.dot_all => seq.push(AST::AnyChar),
                 '.' => seq.push(AST::Dot),
-                '^' => {
-                    if i == 0 {
-                        has_hat = true;
-                    } else {
-                        return Err(ParseError::InvalidHat);
-                    }
-                }
-                '$' => {
-                    if expr.chars().count() - 1 == i {
-                        has_dollar = true;
-                    } else {
-                        return Err(ParseError::InvalidDollar);
-                    }
+                '[' => {
+                    let ast = parse_class(&mut chars, i, flags)?;
+                    seq.push(ast);
                 }
-                _ => seq.push(AST::Char(c)),
+                // ^と$はどこにあっても良い。位置の条件として扱う
+                '^' if flags.multi_line => seq.push(AST::Assert(Assertion::StartLine)),
+                '$' if flags.multi_line => seq.push(AST::Assert(Assertion::EndLine)),
+                '^' => seq.push(AST::Assert(Assertion::StartText)),
+                '$' => seq.push(AST::Assert(Assertion::EndText)),
+                // (?x)では空白を読み飛ばし、#から行末まではコメントとする
+                _ if flags.verbose && c.is_whitespace() => (),
+                '#' if flags.verbose => while chars.next_if(|(_, c)| *c != '\n').is_some() {},
+                _ => seq.push(char_ast(c, flags)),
             },
             ParseState::Escape => {
-                let ast = parse_escape(i, c)?;
+                let ast = match c {
+                    '1'..='9' | 'k' => parse_backref(&mut chars, i, c, &names, flags)?,
+                    _ => parse_escape(&mut chars, i, c, flags)?,
+                };
                 seq.push(ast);
                 state = ParseState::Char;
             }
         }
     }
 
+    // \で終わっている
+    if let ParseState::Escape = state {
+        return Err(ParseError::IncompleteEscape(expr.chars().count() - 1));
+    }
+
     // stackは最終的に空になっているはず。そうでないなら閉じカッコがない
     if !stack.is_empty() {
         return Err(ParseError::NoRightParen);
     }
 
-    // 式が空ならpushはしない
-    if !seq.is_empty() {
+    // 式が空ならpushはしない。a|のように|の後が空の場合は空のSeqをpushする
+    if !seq.is_empty() || !seq_or.is_empty() {
         seq_or.push(AST::Seq(seq));
     }
 
     // Orの生成ができるならそれを返す
     if let Some(ast) = fold_or(seq_or) {
         Ok(AstState {
-            ast: ast,
-            has_hat: has_hat,
-            has_dollar: has_dollar,
+            ast,
+            captures: names.len() - 1,
+            names,
         })
     } else {
         Err(ParseError::Empty)
@@ -1,4 +1,8 @@
 mod engine;
 mod helper;
 
-pub use engine::{do_matching, print};
+pub use engine::{
+    do_matching, print, Captures, Config, Match, Matches, Regex, DEFAULT_MEMO_BUDGET,
+    DEFAULT_RECURSION_LIMIT, DEFAULT_SIZE_LIMIT, DEFAULT_STACK_LIMIT,
+};
+pub use helper::DynError;
@@ -1,7 +1,9 @@
-mod engine;
+// helperはライブラリの非公開モジュールなので、テストのために直接読み込む。DynErrorはlt_regexのものを使う
+#[cfg(test)]
+#[allow(dead_code)]
 mod helper;
 
-use helper::DynError;
+use lt_regex::{DynError, Regex};
 use std::{
     env,
     fs::File,
@@ -14,16 +16,17 @@ fn match_file(expr: &str, file_path: &str) -> Result<(), DynError> {
     let f = File::open(file_path)?;
     let reader = BufReader::new(f);
 
-    engine::print(expr)?;
+    lt_regex::print(expr)?;
     println!();
 
+    // 正規表現のコンパイルは一度だけ行い、各行で使い回す
+    let regex = Regex::new(expr)?;
+
     for line in reader.lines() {
         let line = line?;
         // abcdみたいな入力のときは、abcd, bcd, cd ,cのように入力していく
-        let (result, found) = engine::do_matching(expr, &line, true)?;
-        if result {
-            // resultがtrueなら第2要素には必ず文字列が入っている
-            println!("{}", found.unwrap());
+        if let Some(found) = regex.find(&line)? {
+            println!("{}", found.as_str());
             break;
         }
     }
@@ -46,10 +49,8 @@ fn main() -> Result<(), DynError> {
 
 #[cfg(test)]
 mod tests {
-    use crate::{
-        engine::do_matching,
-        helper::{safe_add, SafeAdd},
-    };
+    use crate::helper::{safe_add, SafeAdd};
+    use lt_regex::{do_matching, Config, Regex, DEFAULT_MEMO_BUDGET};
 
     #[test]
     fn test_safe_add() {
@@ -68,76 +69,1570 @@ mod tests {
 
     #[test]
     fn test_matching() {
-        // parse error
-        assert!(do_matching("+b", "bbb", true).is_err());
-        assert!(do_matching("*b", "bbb", true).is_err());
-        assert!(do_matching("|b", "bbb", true).is_err());
-        assert!(do_matching("?b", "bbb", true).is_err());
-        assert!(do_matching("(abc", "bbb", true).is_err());
-        assert!(do_matching("abc)", "bbb", true).is_err());
+        for is_depth in [true, false] {
+            // parse error
+            assert!(do_matching("+b", "bbb", is_depth).is_err());
+            assert!(do_matching("*b", "bbb", is_depth).is_err());
+            assert!(do_matching("|b", "bbb", is_depth).is_err());
+            assert!(do_matching("?b", "bbb", is_depth).is_err());
+            assert!(do_matching("(abc", "bbb", is_depth).is_err());
+            assert!(do_matching("abc)", "bbb", is_depth).is_err());
 
-        // parse成功でマッチ成功
-        assert!(do_matching("abc|def", "def", true).unwrap().0);
-        assert!(do_matching("(abc)*", "abcabc", true).unwrap().0);
-        assert!(do_matching("(ab|cd)+", "abcdcd", true).unwrap().0);
-        assert!(do_matching("abc?", "abd", true).unwrap().0);
+            // parse成功でマッチ成功
+            assert!(do_matching("abc|def", "def", is_depth).unwrap().0);
+            assert!(do_matching("(abc)*", "abcabc", is_depth).unwrap().0);
+            assert!(do_matching("(ab|cd)+", "abcdcd", is_depth).unwrap().0);
+            assert!(do_matching("abc?", "abd", is_depth).unwrap().0);
 
-        // parse成功でマッチ失敗
-        assert!(!do_matching("abc|def", "efa", true).unwrap().0);
-        assert!(!do_matching("(ab|cd)+", "efa", true).unwrap().0);
-        assert!(!do_matching("abc?", "acb", true).unwrap().0);
+            // parse成功でマッチ失敗
+            assert!(!do_matching("abc|def", "efa", is_depth).unwrap().0);
+            assert!(!do_matching("(ab|cd)+", "efa", is_depth).unwrap().0);
+            assert!(!do_matching("abc?", "acb", is_depth).unwrap().0);
+        }
     }
 
     #[test]
     fn test_matching_multi_byte_characters() {
-        assert!(do_matching("あいう|えお", "あいう", true).unwrap().0);
-        assert!(do_matching("(ワク)*", "ワクワク", true).unwrap().0);
+        for is_depth in [true, false] {
+            assert!(do_matching("あいう|えお", "あいう", is_depth).unwrap().0);
+            assert!(do_matching("(ワク)*", "ワクワク", is_depth).unwrap().0);
 
-        // parse成功でマッチ失敗
-        assert!(!do_matching("ほげ|ふが", "失敗", true).unwrap().0);
-        assert!(!do_matching("(ふー|ばー)+", "ばば", true).unwrap().0);
+            // parse成功でマッチ失敗
+            assert!(!do_matching("ほげ|ふが", "失敗", is_depth).unwrap().0);
+            assert!(!do_matching("(ふー|ばー)+", "ばば", is_depth).unwrap().0);
+        }
     }
 
     #[test]
     fn test_escape文字() {
-        assert!(do_matching("\\.あいう", ".あいうえお", true).unwrap().0);
-        assert!(do_matching("\\?あいう", "?あいうえお", true).unwrap().0);
-        assert!(do_matching("\\+あいう", "+あいうえお", true).unwrap().0);
-        assert!(do_matching("\\*あいう", "*あいうえお", true).unwrap().0);
+        for is_depth in [true, false] {
+            assert!(do_matching("\\.あいう", ".あいうえお", is_depth).unwrap().0);
+            assert!(do_matching("\\?あいう", "?あいうえお", is_depth).unwrap().0);
+            assert!(do_matching("\\+あいう", "+あいうえお", is_depth).unwrap().0);
+            assert!(do_matching("\\*あいう", "*あいうえお", is_depth).unwrap().0);
+
+            // 制御文字
+            let m = do_matching("a\\tb", "x\ta\tb", is_depth).unwrap().1;
+            assert_eq!((2, 5), (m.unwrap().start(), m.unwrap().end()));
+            assert!(
+                do_matching("^[^\\t]+\\t[^\\t]+$", "key\tvalue", is_depth)
+                    .unwrap()
+                    .0
+            );
+            assert!(
+                !do_matching("^[^\\t]+\\t[^\\t]+$", "key\tvalue\tx", is_depth)
+                    .unwrap()
+                    .0
+            );
+            assert!(do_matching("\\r\\n", "line\r\n", is_depth).unwrap().0);
+            assert!(do_matching("a\\0b", "a\0b", is_depth).unwrap().0);
+
+            // コードポイント
+            assert!(do_matching("\\x41\\x62", "Ab", is_depth).unwrap().0);
+            assert!(
+                do_matching("\\x{3042}\\u{3044}", "あい", is_depth)
+                    .unwrap()
+                    .0
+            );
+            assert!(do_matching("\\u{1F600}", "😀", is_depth).unwrap().0);
+            assert!(
+                do_matching("\\u{200B}", "ゼロ幅\u{200B}空白", is_depth)
+                    .unwrap()
+                    .0
+            );
+            assert!(
+                do_matching("[\\x{3041}-\\x{3093}]+", "ひらがな", is_depth)
+                    .unwrap()
+                    .0
+            );
+            assert!(
+                !do_matching("[\\x{3041}-\\x{3093}]", "カタカナ", is_depth)
+                    .unwrap()
+                    .0
+            );
+            assert!(do_matching("[\\t\\n]", "\n", is_depth).unwrap().0);
+
+            // parse error
+            assert!(do_matching("\\x4", "A", is_depth).is_err());
+            assert!(do_matching("\\x4G", "A", is_depth).is_err());
+            assert!(do_matching("\\x{}", "A", is_depth).is_err());
+            assert!(do_matching("\\x{41", "A", is_depth).is_err());
+            assert!(do_matching("\\x{110000}", "A", is_depth).is_err());
+            assert!(do_matching("\\x{FFFFFFFFFF}", "A", is_depth).is_err());
+            assert!(do_matching("\\u{D800}", "A", is_depth).is_err());
+            assert!(do_matching("\\u3042", "あ", is_depth).is_err());
+            assert!(do_matching("[\\x{ZZ}]", "A", is_depth).is_err());
+            assert!(do_matching("abc\\", "abc", is_depth).is_err());
+        }
+    }
+
+    #[test]
+    fn test_エスケープのエラー位置() {
+        let err = Regex::new("ab\\x4G").unwrap_err();
+        assert_eq!(
+            "ParseError: invalid hex digit: pos = 5, char = 'G'",
+            err.to_string()
+        );
+        let err = Regex::new("\\u{110000}").unwrap_err();
+        assert_eq!(
+            "ParseError: code point too large: pos = 1, code point = 0x110000",
+            err.to_string()
+        );
+        let err = Regex::new("あ\\x{DFFF}").unwrap_err();
+        assert_eq!(
+            "ParseError: surrogate code point: pos = 2, code point = 0xDFFF",
+            err.to_string()
+        );
+        let err = Regex::new("\\x{12").unwrap_err();
+        assert_eq!("ParseError: incomplete escape: pos = 1", err.to_string());
     }
 
     #[test]
     fn test_ドットによる任意の1文字のマッチング() {
-        assert!(do_matching("あ.か", "あいかえお", true).unwrap().0);
-        assert!(do_matching(".か.", "あいかえお", true).unwrap().0);
-        assert!(do_matching("か..け", "かきくけこ", true).unwrap().0);
+        for is_depth in [true, false] {
+            assert!(do_matching("あ.か", "あいかえお", is_depth).unwrap().0);
+            assert!(do_matching(".か.", "あいかえお", is_depth).unwrap().0);
+            assert!(do_matching("か..け", "かきくけこ", is_depth).unwrap().0);
 
-        // // 失敗パターン
-        assert!(!do_matching("い.え", "あいえお", true).unwrap().0);
-        assert!(!do_matching(".あ.", "かきくけこ", true).unwrap().0);
+            // // 失敗パターン
+            assert!(!do_matching("い.え", "あいえお", is_depth).unwrap().0);
+            assert!(!do_matching(".あ.", "かきくけこ", is_depth).unwrap().0);
+        }
     }
 
     #[test]
     fn test_ハットでの先頭一致() {
-        assert!(do_matching("^あいう", "あいうえお", true).unwrap().0);
-        assert!(!do_matching("^あいう", "えおあいう", true).unwrap().0);
+        for is_depth in [true, false] {
+            assert!(do_matching("^あいう", "あいうえお", is_depth).unwrap().0);
+            assert!(!do_matching("^あいう", "えおあいう", is_depth).unwrap().0);
+        }
     }
 
     #[test]
     fn test_ダラーでの後方一致() {
-        assert!(do_matching("うえお$", "あいうえお", true).unwrap().0);
-        assert!(!do_matching("うえお$", "うえおか", true).unwrap().0);
+        for is_depth in [true, false] {
+            assert!(do_matching("うえお$", "あいうえお", is_depth).unwrap().0);
+            assert!(!do_matching("うえお$", "うえおか", is_depth).unwrap().0);
+        }
+    }
+
+    #[test]
+    fn test_ハットとダラーをどこにでも書ける() {
+        for is_depth in [true, false] {
+            // 選択の中
+            let m = do_matching("^foo|bar$", "xxbar", is_depth).unwrap().1;
+            assert_eq!("bar", m.unwrap().as_str());
+            let m = do_matching("^foo|bar$", "fooxx", is_depth).unwrap().1;
+            assert_eq!("foo", m.unwrap().as_str());
+            assert!(!do_matching("^foo|bar$", "xfoobarx", is_depth).unwrap().0);
+
+            // グループの中
+            let m = do_matching("(^a|b)+", "aab", is_depth).unwrap().1;
+            assert_eq!("a", m.unwrap().as_str());
+            let m = do_matching("x(^a|b)", "xaxb", is_depth).unwrap().1;
+            assert_eq!((2, 4), (m.unwrap().start(), m.unwrap().end()));
+            let m = do_matching("(a|b$)c?", "bab", is_depth).unwrap().1;
+            assert_eq!((1, 2), (m.unwrap().start(), m.unwrap().end()));
+
+            // 先頭や末尾以外に書くと、その位置が先頭や末尾でない限りマッチしない
+            assert!(!do_matching("a^b", "ab", is_depth).unwrap().0);
+            assert!(!do_matching("a$b", "ab", is_depth).unwrap().0);
+            assert!(do_matching("a$|^b", "ba", is_depth).unwrap().0);
+            assert!(do_matching("^$", "", is_depth).unwrap().0);
+            assert!(!do_matching("^$", "a", is_depth).unwrap().0);
+        }
     }
 
     #[test]
     fn test_ハットとダラーで完全一致() {
-        assert!(do_matching("^あいうえお$", "あいうえお", true).unwrap().0);
-        assert!(!do_matching("^あいうえお$", "あいうえおか", true).unwrap().0);
-        assert!(!do_matching("^あいうえお$", "んあいうえお", true).unwrap().0);
+        for is_depth in [true, false] {
+            assert!(
+                do_matching("^あいうえお$", "あいうえお", is_depth)
+                    .unwrap()
+                    .0
+            );
+            assert!(
+                !do_matching("^あいうえお$", "あいうえおか", is_depth)
+                    .unwrap()
+                    .0
+            );
+            assert!(
+                !do_matching("^あいうえお$", "んあいうえお", is_depth)
+                    .unwrap()
+                    .0
+            );
+            assert!(
+                !do_matching("^あいうえお$", "んあいうえおか", is_depth)
+                    .unwrap()
+                    .0
+            );
+        }
+    }
+
+    #[test]
+    fn test_幅優先探索で指数的な爆発をしない() {
+        // a?^n a^n は深さ優先探索だと2^nの分岐を辿るが、幅優先探索ならスレッド数が命令数で抑えられる
+        let n = 64;
+        let expr = format!("{}{}", "a?".repeat(n), "a".repeat(n));
+        let line = "a".repeat(n);
+        assert!(do_matching(&expr, &line, false).unwrap().0);
+        assert!(!do_matching(&expr, &line[1..], false).unwrap().0);
+    }
+
+    #[test]
+    fn test_マッチした範囲() {
+        for is_depth in [true, false] {
+            let m = do_matching("abc", "xxabcyy", is_depth).unwrap().1.unwrap();
+            assert_eq!((2, 5), (m.start(), m.end()));
+            assert_eq!("abc", m.as_str());
+
+            // 貪欲にマッチする
+            let m = do_matching("(ab|cd)+", "xabcdcdy", is_depth)
+                .unwrap()
+                .1
+                .unwrap();
+            assert_eq!("abcdcd", m.as_str());
+
+            // 優先度の高い分岐が選ばれる
+            let m = do_matching("a|ab", "ab", is_depth).unwrap().1.unwrap();
+            assert_eq!("a", m.as_str());
+
+            // バイト位置で返す
+            let m = do_matching("いう", "あいうえお", is_depth)
+                .unwrap()
+                .1
+                .unwrap();
+            assert_eq!((3, 9), (m.start(), m.end()));
+            assert_eq!("いう", m.as_str());
+
+            let m = do_matching("うえお$", "あいうえお", is_depth)
+                .unwrap()
+                .1
+                .unwrap();
+            assert_eq!((6, 15), (m.start(), m.end()));
+
+            assert!(do_matching("abc", "xxabyy", is_depth).unwrap().1.is_none());
+        }
+    }
+
+    #[test]
+    fn test_regex() {
+        for is_depth in [true, false] {
+            let re = Regex::with_mode("(ab|cd)+", is_depth).unwrap();
+            assert!(re.is_match("xxcdab").unwrap());
+            assert!(!re.is_match("acbd").unwrap());
+
+            let m = re.find("xxabcdyy").unwrap().unwrap();
+            assert_eq!("abcd", m.as_str());
+
+            let found = re
+                .find_iter("ab-cdcd-x-ab")
+                .map(|m| m.unwrap().as_str())
+                .collect::<Vec<&str>>();
+            assert_eq!(vec!["ab", "cdcd", "ab"], found);
+
+            // 空文字列へのマッチでも無限ループしない
+            let re = Regex::with_mode("a*", is_depth).unwrap();
+            let found = re
+                .find_iter("baab")
+                .map(|m| {
+                    let m = m.unwrap();
+                    (m.start(), m.end())
+                })
+                .collect::<Vec<(usize, usize)>>();
+            assert_eq!(vec![(0, 0), (1, 3), (3, 3), (4, 4)], found);
+
+            // ^があるときは先頭にしかマッチしない
+            let re = Regex::with_mode("^ab", is_depth).unwrap();
+            assert_eq!(1, re.find_iter("ababab").count());
+        }
+
+        assert!(Regex::new("(ab").is_err());
+    }
+
+    #[test]
+    fn test_regexはスレッド間で共有できる() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Regex>();
+
+        let re = std::sync::Arc::new(Regex::new("あ(い|う)+").unwrap());
+        let handles = (0..4)
+            .map(|_| {
+                let re = re.clone();
+                std::thread::spawn(move || re.is_match("ああいういう").unwrap())
+            })
+            .collect::<Vec<_>>();
+        for h in handles {
+            assert!(h.join().unwrap());
+        }
+    }
+
+    #[test]
+    fn test_文字クラス() {
+        for is_depth in [true, false] {
+            assert!(do_matching("[abc]", "xxbxx", is_depth).unwrap().0);
+            assert!(!do_matching("[abc]", "xyz", is_depth).unwrap().0);
+
+            // 範囲
+            let m = do_matching("[0-9]+", "abc2024def", is_depth).unwrap().1;
+            assert_eq!("2024", m.unwrap().as_str());
+            assert!(do_matching("[a-cx-z]", "y", is_depth).unwrap().0);
+
+            // 否定
+            let m = do_matching("[^0-9]+", "123abc456", is_depth).unwrap().1;
+            assert_eq!("abc", m.unwrap().as_str());
+            assert!(!do_matching("^[^a-z]", "abc", is_depth).unwrap().0);
+
+            // エスケープと、末尾の-はただの文字
+            assert!(do_matching("[\\]]", "a]b", is_depth).unwrap().0);
+            assert!(do_matching("[a\\-z]", "-", is_depth).unwrap().0);
+            assert!(!do_matching("[a\\-z]", "b", is_depth).unwrap().0);
+            assert!(do_matching("[az-]", "-", is_depth).unwrap().0);
+            assert!(do_matching("[(|)]", "|", is_depth).unwrap().0);
+
+            // マルチバイト文字の範囲
+            let m = do_matching("[ぁ-ん]+", "カタカナとひらがな", is_depth)
+                .unwrap()
+                .1;
+            assert_eq!("とひらがな", m.unwrap().as_str());
+            assert!(!do_matching("[^ぁ-ん]", "あいう", is_depth).unwrap().0);
+
+            // parse error
+            assert!(do_matching("[abc", "abc", is_depth).is_err());
+            assert!(do_matching("[]", "abc", is_depth).is_err());
+            assert!(do_matching("[z-a]", "abc", is_depth).is_err());
+            assert!(do_matching("[\\q]", "abc", is_depth).is_err());
+        }
+    }
+
+    #[test]
+    fn test_回数指定の繰り返し() {
+        for is_depth in [true, false] {
+            let m = do_matching("a{3}", "aaaaa", is_depth).unwrap().1;
+            assert_eq!("aaa", m.unwrap().as_str());
+            assert!(!do_matching("a{3}", "aab", is_depth).unwrap().0);
+
+            let m = do_matching("a{2,}", "baaaaab", is_depth).unwrap().1;
+            assert_eq!("aaaaa", m.unwrap().as_str());
+            assert!(!do_matching("a{2,}", "abab", is_depth).unwrap().0);
+
+            let m = do_matching("a{2,3}", "aaaaa", is_depth).unwrap().1;
+            assert_eq!("aaa", m.unwrap().as_str());
+            let m = do_matching("x(ab){1,2}y", "xababy", is_depth).unwrap().1;
+            assert_eq!("xababy", m.unwrap().as_str());
+            assert!(!do_matching("x(ab){1,2}y", "xabababy", is_depth).unwrap().0);
+
+            let m = do_matching("[0-9]{3}-[0-9]{4}", "tel: 123-4567", is_depth)
+                .unwrap()
+                .1;
+            assert_eq!("123-4567", m.unwrap().as_str());
+            let m = do_matching("あ{0}い", "あい", is_depth).unwrap().1;
+            assert_eq!("い", m.unwrap().as_str());
+
+            // エスケープすればただの文字
+            assert!(do_matching("\\{1\\}", "{1}", is_depth).unwrap().0);
+
+            // parse error
+            assert!(do_matching("{2}", "aa", is_depth).is_err());
+            assert!(do_matching("a{", "aa", is_depth).is_err());
+            assert!(do_matching("a{,2}", "aa", is_depth).is_err());
+            assert!(do_matching("a{2", "aa", is_depth).is_err());
+            assert!(do_matching("a{3,2}", "aa", is_depth).is_err());
+            assert!(do_matching("a{1x}", "aa", is_depth).is_err());
+            assert!(do_matching("a{99999999999999999999999}", "aa", is_depth).is_err());
+        }
+    }
+
+    #[test]
+    fn test_命令数の上限() {
+        // 展開後の命令数が上限を超えたらエラーになる
+        assert!(Regex::new("(abc){100000}").is_err());
+        assert!(Regex::new("a{1000}{1000}").is_err());
+
+        let config = Config {
+            size_limit: 10,
+            ..Default::default()
+        };
+        assert!(Regex::with_config("a{9}", &config).is_ok());
+        assert!(Regex::with_config("a{10}", &config).is_err());
+    }
+
+    #[test]
+    fn test_キャプチャ() {
+        for is_depth in [true, false] {
+            let re = Regex::with_mode("([a-z]+)=([0-9]+)", is_depth).unwrap();
+            assert_eq!(3, re.captures_len());
+            let caps = re.captures("key: size=42;").unwrap().unwrap();
+            assert_eq!("size=42", caps.get(0).unwrap().as_str());
+            assert_eq!("size", caps.get(1).unwrap().as_str());
+            let m = caps.get(2).unwrap();
+            assert_eq!((10, 12), (m.start(), m.end()));
+            assert_eq!("42", m.as_str());
+            assert!(caps.get(3).is_none());
+            assert!(re.captures("no match").unwrap().is_none());
+
+            // 入れ子のグループは(の順に番号が振られる
+            let re = Regex::with_mode("((あ)(い|う))+", is_depth).unwrap();
+            let caps = re.captures("ああいあう").unwrap().unwrap();
+            assert_eq!("あいあう", caps.get(0).unwrap().as_str());
+            // 繰り返した場合は最後の繰り返しの範囲になる
+            assert_eq!("あう", caps.get(1).unwrap().as_str());
+            assert_eq!("あ", caps.get(2).unwrap().as_str());
+            assert_eq!("う", caps.get(3).unwrap().as_str());
+
+            // マッチに参加しなかったグループはNone
+            let re = Regex::with_mode("(a)|(b)", is_depth).unwrap();
+            let caps = re.captures("b").unwrap().unwrap();
+            assert!(caps.get(1).is_none());
+            assert_eq!("b", caps.get(2).unwrap().as_str());
+
+            // 失敗した分岐で保存した位置は残らない
+            let re = Regex::with_mode("(a)x|ay", is_depth).unwrap();
+            let caps = re.captures("ay").unwrap().unwrap();
+            assert!(caps.get(1).is_none());
+
+            // 空のグループは空文字列をキャプチャする
+            let re = Regex::with_mode("a()b", is_depth).unwrap();
+            let caps = re.captures("ab").unwrap().unwrap();
+            assert_eq!("", caps.get(1).unwrap().as_str());
+        }
+    }
+
+    #[test]
+    fn test_名前付きグループと非キャプチャグループ() {
+        for is_depth in [true, false] {
+            let re = Regex::with_mode("(?P<key>[a-z]+)=(?<value>[0-9]+)", is_depth).unwrap();
+            let names = re.capture_names().collect::<Vec<Option<&str>>>();
+            assert_eq!(vec![None, Some("key"), Some("value")], names);
+
+            let caps = re.captures("size=42").unwrap().unwrap();
+            assert_eq!("size", caps.name("key").unwrap().as_str());
+            assert_eq!("42", caps.name("value").unwrap().as_str());
+            assert_eq!("42", caps.get(2).unwrap().as_str());
+            assert!(caps.name("unknown").is_none());
+
+            // 非キャプチャグループは番号を消費しない
+            let re = Regex::with_mode("(?:ab|cd)+(x)(?:y)(?<z>z)", is_depth).unwrap();
+            assert_eq!(3, re.captures_len());
+            let caps = re.captures("abcdxyz").unwrap().unwrap();
+            assert_eq!("abcdxyz", caps.get(0).unwrap().as_str());
+            assert_eq!("x", caps.get(1).unwrap().as_str());
+            assert_eq!("z", caps.name("z").unwrap().as_str());
+            assert!(do_matching("a(?:)b", "ab", is_depth).unwrap().0);
+
+            // parse error
+            assert!(do_matching("(?P<>a)", "a", is_depth).is_err());
+            assert!(do_matching("(?<1a>a)", "a", is_depth).is_err());
+            assert!(do_matching("(?<a-b>a)", "a", is_depth).is_err());
+            assert!(do_matching("(?<a", "a", is_depth).is_err());
+            assert!(do_matching("(?Pa>a)", "a", is_depth).is_err());
+            assert!(do_matching("(?<a>a)(?<a>b)", "ab", is_depth).is_err());
+            assert!(do_matching("(?%a)", "a", is_depth).is_err());
+            assert!(do_matching("(?", "a", is_depth).is_err());
+        }
+    }
+
+    #[test]
+    fn test_非貪欲な量指定子() {
+        for is_depth in [true, false] {
+            let m = do_matching("a+?", "aaa", is_depth).unwrap().1;
+            assert_eq!("a", m.unwrap().as_str());
+            let m = do_matching("a*?", "aaa", is_depth).unwrap().1;
+            assert_eq!("", m.unwrap().as_str());
+            let m = do_matching("a??", "aaa", is_depth).unwrap().1;
+            assert_eq!("", m.unwrap().as_str());
+            let m = do_matching("a{2,4}?", "aaaaa", is_depth).unwrap().1;
+            assert_eq!("aa", m.unwrap().as_str());
+            let m = do_matching("a{2,}?", "aaaaa", is_depth).unwrap().1;
+            assert_eq!("aa", m.unwrap().as_str());
+
+            // 後ろが続く場合は、マッチする範囲で最短になる
+            let m = do_matching("<.+?>", "<a><b>", is_depth).unwrap().1;
+            assert_eq!("<a>", m.unwrap().as_str());
+            let m = do_matching("<.+>", "<a><b>", is_depth).unwrap().1;
+            assert_eq!("<a><b>", m.unwrap().as_str());
+            let m = do_matching("あ.*?う", "あいうあいう", is_depth).unwrap().1;
+            assert_eq!("あいう", m.unwrap().as_str());
+            let m = do_matching("x(ab)??ab", "xabab", is_depth).unwrap().1;
+            assert_eq!("xab", m.unwrap().as_str());
+
+            let re = Regex::with_mode("(.+?)(\\.[a-z]+)*$", is_depth).unwrap();
+            let caps = re.captures("archive.tar.gz").unwrap().unwrap();
+            assert_eq!("archive", caps.get(1).unwrap().as_str());
+            assert_eq!(".gz", caps.get(2).unwrap().as_str());
+        }
+    }
+
+    #[test]
+    fn test_文字クラスの略記() {
+        for is_depth in [true, false] {
+            let m = do_matching("\\d+", "abc123def", is_depth).unwrap().1;
+            assert_eq!("123", m.unwrap().as_str());
+            let m = do_matching("\\D+", "123abc456", is_depth).unwrap().1;
+            assert_eq!("abc", m.unwrap().as_str());
+            let m = do_matching("\\w+", "  foo_bar1  ", is_depth).unwrap().1;
+            assert_eq!("foo_bar1", m.unwrap().as_str());
+            let m = do_matching("\\W+", "foo, bar", is_depth).unwrap().1;
+            assert_eq!(", ", m.unwrap().as_str());
+            let m = do_matching("\\s+", "foo \t bar", is_depth).unwrap().1;
+            assert_eq!(" \t ", m.unwrap().as_str());
+            let m = do_matching("\\S+", "  foo  ", is_depth).unwrap().1;
+            assert_eq!("foo", m.unwrap().as_str());
+
+            // 文字クラスの中でも使える
+            let m = do_matching("[\\d_-]+", "ab12_3-4cd", is_depth).unwrap().1;
+            assert_eq!("12_3-4", m.unwrap().as_str());
+            let m = do_matching("[^\\s,]+", " , foo,bar", is_depth).unwrap().1;
+            assert_eq!("foo", m.unwrap().as_str());
+            assert!(!do_matching("[\\D]", "123", is_depth).unwrap().0);
+
+            // Unicodeの定義では全角数字やひらがな、全角空白も含む
+            let m = do_matching("\\d+", "値は１２３です", is_depth).unwrap().1;
+            assert_eq!("１２３", m.unwrap().as_str());
+            let m = do_matching("\\w+", "「ひらがなカタカナ漢字」", is_depth)
+                .unwrap()
+                .1;
+            assert_eq!("ひらがなカタカナ漢字", m.unwrap().as_str());
+            let m = do_matching("\\s", "あ\u{3000}い", is_depth).unwrap().1;
+            assert_eq!("\u{3000}", m.unwrap().as_str());
+
+            // ASCIIのみの定義
+            let config = Config {
+                is_depth,
+                unicode: false,
+                ..Default::default()
+            };
+            let re = Regex::with_config("\\d+", &config).unwrap();
+            assert!(!re.is_match("１２３").unwrap());
+            let re = Regex::with_config("\\w+", &config).unwrap();
+            assert_eq!("abc", re.find("ひらabcがな").unwrap().unwrap().as_str());
+            let re = Regex::with_config("[\\W]+", &config).unwrap();
+            assert_eq!("ひら", re.find("ひらabcがな").unwrap().unwrap().as_str());
+            let re = Regex::with_config("\\s", &config).unwrap();
+            assert!(!re.is_match("\u{3000}").unwrap());
+        }
+    }
+
+    #[test]
+    fn test_位置の条件() {
+        for is_depth in [true, false] {
+            // 単語の境界
+            let m = do_matching("\\bcat\\b", "concat cat cats", is_depth)
+                .unwrap()
+                .1;
+            assert_eq!((7, 10), (m.unwrap().start(), m.unwrap().end()));
+            assert!(!do_matching("\\bcat\\b", "concat cats", is_depth).unwrap().0);
+            let m = do_matching("\\Bcat", "cat concat", is_depth).unwrap().1;
+            assert_eq!(7, m.unwrap().start());
+            let m = do_matching("\\b\\w+\\b", "  hello  ", is_depth).unwrap().1;
+            assert_eq!("hello", m.unwrap().as_str());
+            assert!(do_matching("\\b", "a", is_depth).unwrap().0);
+            assert!(!do_matching("\\b", " ", is_depth).unwrap().0);
+            assert!(do_matching("\\B", "", is_depth).unwrap().0);
+
+            // Unicodeの単語の境界
+            let m = do_matching("\\bねこ\\b", "こねこ ねこ", is_depth)
+                .unwrap()
+                .1;
+            assert_eq!("ねこ", m.unwrap().as_str());
+            assert_eq!(10, m.unwrap().start());
+            let config = Config {
+                is_depth,
+                unicode: false,
+                ..Default::default()
+            };
+            let re = Regex::with_config("\\bab", &config).unwrap();
+            assert!(re.is_match("あab").unwrap());
+
+            // 文字列の先頭と末尾
+            assert!(do_matching("\\Aab", "abc", is_depth).unwrap().0);
+            assert!(!do_matching("\\Abc", "abc", is_depth).unwrap().0);
+            assert!(do_matching("bc\\z", "abc", is_depth).unwrap().0);
+            assert!(!do_matching("ab\\z", "abc", is_depth).unwrap().0);
+
+            // パターンのどこにあっても良い
+            let m = do_matching("(\\Aa|b\\z)", "bab", is_depth).unwrap().1;
+            assert_eq!((2, 3), (m.unwrap().start(), m.unwrap().end()));
+            let m = do_matching("x*\\b(\\w+)", "xx yy", is_depth).unwrap().1;
+            assert_eq!("xx", m.unwrap().as_str());
+        }
+    }
+
+    #[test]
+    fn test_インラインフラグ() {
+        for is_depth in [true, false] {
+            // (?i) 大文字小文字を区別しない
+            let m = do_matching("(?i)hello", "say HeLLo", is_depth).unwrap().1;
+            assert_eq!("HeLLo", m.unwrap().as_str());
+            assert!(do_matching("(?i)[a-c]+x", "ABCX", is_depth).unwrap().0);
+            assert!(!do_matching("(?i)[^a-c]", "aBc", is_depth).unwrap().0);
+            assert!(do_matching("(?i)\\x41", "a", is_depth).unwrap().0);
+            assert!(!do_matching("hello", "HELLO", is_depth).unwrap().0);
+
+            // フラグの効果はグループの終わりまで
+            assert!(do_matching("a(?i:b)c", "aBc", is_depth).unwrap().0);
+            assert!(!do_matching("a(?i:b)c", "aBC", is_depth).unwrap().0);
+            assert!(do_matching("a((?i)b)c", "aBc", is_depth).unwrap().0);
+            assert!(!do_matching("a((?i)b)c", "aBC", is_depth).unwrap().0);
+            assert!(!do_matching("(?i)a(?-i:b)", "AB", is_depth).unwrap().0);
+            assert!(do_matching("(?i)a(?-i:b)", "Ab", is_depth).unwrap().0);
+
+            // (?i:...)はキャプチャしない
+            let re = Regex::with_mode("(?i:a)(b)", is_depth).unwrap();
+            let caps = re.captures("Ab").unwrap().unwrap();
+            assert_eq!("b", caps.get(1).unwrap().as_str());
+
+            // (?m) ^と$が行の先頭と末尾にもマッチする
+            let m = do_matching("^b$", "a\nb\nc", is_depth).unwrap();
+            assert!(!m.0);
+            let m = do_matching("(?m)^b$", "a\nb\nc", is_depth).unwrap().1;
+            assert_eq!((2, 3), (m.unwrap().start(), m.unwrap().end()));
+            assert!(do_matching("(?m)c$", "a\nc", is_depth).unwrap().0);
+
+            // (?s) .が改行にもマッチする
+            assert!(!do_matching("a.b", "a\nb", is_depth).unwrap().0);
+            assert!(do_matching("(?s)a.b", "a\nb", is_depth).unwrap().0);
+            assert!(do_matching("a.b", "a b", is_depth).unwrap().0);
+
+            // (?x) 空白とコメントを無視する
+            let m = do_matching("(?x) a b # コメント\n c", "xabc", is_depth)
+                .unwrap()
+                .1;
+            assert_eq!("abc", m.unwrap().as_str());
+            assert!(do_matching("(?x)a\\ b\\#", "a b#", is_depth).unwrap().0);
+            assert!(do_matching("(?x)[ ]", " ", is_depth).unwrap().0);
+
+            // 複数のフラグ
+            assert!(do_matching("(?is)A.B", "a\nb", is_depth).unwrap().0);
+        }
+
+        // 不正なフラグ
+        assert!(do_matching("(?q)a", "a", true).is_err());
+        assert!(do_matching("(?)a", "a", true).is_err());
+        assert!(do_matching("(?i", "a", true).is_err());
+    }
+
+    #[test]
+    fn test_大文字小文字を区別しないマッチ() {
+        for is_depth in [true, false] {
+            // ギリシャ文字。σとςはどちらもΣと同じ文字に畳み込まれる
+            assert!(do_matching("(?i)ΟΔΥΣΣΕΥΣ", "οδυσσευς", is_depth).unwrap().0);
+            assert!(do_matching("(?i)[α-ω]+", "ΑΒΓ", is_depth).unwrap().0);
+            assert!(do_matching("(?i)ς", "Σ", is_depth).unwrap().0);
+
+            // キリル文字
+            let m = do_matching("(?i)москва", "МОСКВА", is_depth).unwrap().1;
+            assert_eq!("МОСКВА", m.unwrap().as_str());
+            assert!(do_matching("(?i)[а-я]", "Ж", is_depth).unwrap().0);
+
+            // ケルビン記号(U+212A)はkと同じ文字として扱う
+            assert!(do_matching("(?i)k", "\u{212A}", is_depth).unwrap().0);
+            assert!(do_matching("(?i)\u{212A}", "K", is_depth).unwrap().0);
+            assert!(do_matching("(?i)[a-z]", "\u{212A}", is_depth).unwrap().0);
+            assert!(!do_matching("(?i)[^k]", "\u{212A}", is_depth).unwrap().0);
+            assert!(!do_matching("k", "\u{212A}", is_depth).unwrap().0);
+
+            // 全角のラテン文字
+            assert!(do_matching("(?i)ＡＢＣ", "ａｂｃ", is_depth).unwrap().0);
+            assert!(do_matching("(?i)[ａ-ｚ]+", "ＲＵＳＴ", is_depth).unwrap().0);
+
+            // ドイツ語。ẞ(U+1E9E)はßに畳み込まれるが、ssへの変換は単純な畳み込みに含まれない
+            assert!(do_matching("(?i)straße", "STRAẞE", is_depth).unwrap().0);
+            assert!(!do_matching("(?i)straße", "STRASSE", is_depth).unwrap().0);
+
+            // 日本語には大文字小文字がないので影響しない
+            assert!(do_matching("(?i)ねこ", "ねこ", is_depth).unwrap().0);
+            assert!(!do_matching("(?i)ねこ", "ネコ", is_depth).unwrap().0);
+
+            // 設定でパターン全体を大文字小文字を区別しないようにする
+            let config = Config {
+                is_depth,
+                case_insensitive: true,
+                ..Default::default()
+            };
+            let re = Regex::with_config("hello wörld", &config).unwrap();
+            assert!(re.is_match("HELLO WÖRLD").unwrap());
+            let re = Regex::with_config("a(?-i)b", &config).unwrap();
+            assert!(re.is_match("Ab").unwrap());
+            assert!(!re.is_match("AB").unwrap());
+        }
+    }
+
+    #[test]
+    fn test_ユニコードの性質() {
+        for is_depth in [true, false] {
+            // 用字
+            let m = do_matching("\\p{Katakana}+", "ひらがなとカタカナー", is_depth)
+                .unwrap()
+                .1;
+            assert_eq!("カタカナー", m.unwrap().as_str());
+            let m = do_matching("\\p{Hiragana}+", "漢字とひらがな", is_depth)
+                .unwrap()
+                .1;
+            assert_eq!("とひらがな", m.unwrap().as_str());
+            let m = do_matching("\\p{Han}+", "ひらがなと漢字", is_depth)
+                .unwrap()
+                .1;
+            assert_eq!("漢字", m.unwrap().as_str());
+            assert!(do_matching("\\p{Greek}", "αβγ", is_depth).unwrap().0);
+            assert!(!do_matching("\\p{Greek}", "abc", is_depth).unwrap().0);
+            // sc=はScript、scx=はScript_Extensionsで判定する。、はScriptでは共通の文字
+            let m = do_matching("\\p{sc=Han}+", "漢字、かな", is_depth)
+                .unwrap()
+                .1;
+            assert_eq!("漢字", m.unwrap().as_str());
+            assert!(!do_matching("\\p{sc=Han}", "、", is_depth).unwrap().0);
+            let m = do_matching("\\p{scx=Han}+", "漢字、かな", is_depth)
+                .unwrap()
+                .1;
+            assert_eq!("漢字、", m.unwrap().as_str());
+
+            // 一般カテゴリ
+            let m = do_matching("\\p{Lu}\\p{Ll}+", "hello World", is_depth)
+                .unwrap()
+                .1;
+            assert_eq!("World", m.unwrap().as_str());
+            assert!(do_matching("\\pL", "あ", is_depth).unwrap().0);
+            assert!(!do_matching("\\p{L}", "123", is_depth).unwrap().0);
+            assert!(do_matching("\\p{Letter}", "Ж", is_depth).unwrap().0);
+            assert!(do_matching("\\p{gc=Nd}", "٣", is_depth).unwrap().0);
+
+            // 否定
+            let m = do_matching("\\P{L}+", "abc123def", is_depth).unwrap().1;
+            assert_eq!("123", m.unwrap().as_str());
+
+            // 文字クラスの中でも使える
+            let m = do_matching(
+                "[\\p{Hiragana}\\p{Katakana}]+",
+                "漢字ひらがなカタカナ漢字",
+                is_depth,
+            )
+            .unwrap()
+            .1;
+            assert_eq!("ひらがなカタカナ", m.unwrap().as_str());
+            let m = do_matching("[^\\P{Han}]+", "ひらがなと漢字", is_depth)
+                .unwrap()
+                .1;
+            assert_eq!("漢字", m.unwrap().as_str());
+        }
+
+        // 未知の性質や閉じていない{}
+        assert!(do_matching("\\p{Klingon}", "a", true).is_err());
+        assert!(do_matching("\\p{Han", "a", true).is_err());
+        assert!(do_matching("[\\p{Klingon}]", "a", true).is_err());
+        assert!(do_matching("\\p", "a", true).is_err());
+    }
+
+    #[test]
+    fn test_posixクラス() {
+        // クラスの名前と、含まれる文字、含まれない文字
+        let cases = [
+            ("alnum", "aZ5", "_ -"),
+            ("alpha", "aZ", "5_あ"),
+            ("blank", " \t", "\na"),
+            ("cntrl", "\0\x1f\x7f", " a"),
+            ("digit", "09", "a٣"),
+            ("graph", "!~a", " \t"),
+            ("lower", "az", "AZ"),
+            ("print", " ~a", "\t\x7f"),
+            ("punct", "!/:@[`{~", "a0 "),
+            ("space", " \t\n\r\x0b\x0c", "a\u{3000}"),
+            ("upper", "AZ", "az"),
+            ("xdigit", "09afAF", "gG"),
+        ];
+
+        for is_depth in [true, false] {
+            for (name, yes, no) in cases {
+                let re = Regex::with_mode(&format!("^[[:{name}:]]$"), is_depth).unwrap();
+                let not_re = Regex::with_mode(&format!("^[[:^{name}:]]$"), is_depth).unwrap();
+                for c in yes.chars() {
+                    assert!(re.is_match(&c.to_string()).unwrap(), "{name} {c:?}");
+                    assert!(!not_re.is_match(&c.to_string()).unwrap(), "{name} {c:?}");
+                }
+                for c in no.chars() {
+                    assert!(!re.is_match(&c.to_string()).unwrap(), "{name} {c:?}");
+                    assert!(not_re.is_match(&c.to_string()).unwrap(), "{name} {c:?}");
+                }
+            }
+
+            // 他の文字や範囲と組み合わせる
+            let m = do_matching("[[:digit:]a-c_]+", "xx12ab_3yy", is_depth)
+                .unwrap()
+                .1;
+            assert_eq!("12ab_3", m.unwrap().as_str());
+            assert!(
+                !do_matching("[^[:alpha:][:space:]]", "ab cd", is_depth)
+                    .unwrap()
+                    .0
+            );
+
+            // [:name:]の形でなければ通常の文字として扱う
+            assert!(do_matching("[\\[:]+", ":[", is_depth).unwrap().0);
+        }
+
+        // 未知のクラス
+        assert!(do_matching("[[:foo:]]", "a", true).is_err());
+        assert!(do_matching("[[:^Alpha:]]", "a", true).is_err());
+    }
+
+    #[test]
+    fn test_文字クラスの集合演算() {
+        for is_depth in [true, false] {
+            // 入れ子の文字クラスは和集合
+            let m = do_matching("[a-c[x-z]]+", "dabzyq", is_depth).unwrap().1;
+            assert_eq!("abzy", m.unwrap().as_str());
+            let m = do_matching("[a-z&&[^aeiou]]+", "aeibcdo", is_depth)
+                .unwrap()
+                .1;
+            assert_eq!("bcd", m.unwrap().as_str());
+
+            // 積集合。ASCIIでもある文字
+            let m = do_matching("[\\p{L}&&[\\x00-\\x7F]]+", "あいabcう", is_depth)
+                .unwrap()
+                .1;
+            assert_eq!("abc", m.unwrap().as_str());
+
+            // 差集合。特定の漢字を除く漢字
+            let m = do_matching("[\\p{Han}--[一二三]]+", "一二三四五六", is_depth)
+                .unwrap()
+                .1;
+            assert_eq!("四五六", m.unwrap().as_str());
+            assert!(!do_matching("[0-9--[5]]", "5", is_depth).unwrap().0);
+
+            // 対称差
+            let re = Regex::with_mode("^[a-f~~d-z]+$", is_depth).unwrap();
+            assert!(re.is_match("abcxyz").unwrap());
+            assert!(!re.is_match("e").unwrap());
+
+            // 演算は左から順に行う
+            assert!(do_matching("[a-z--[a-m]&&[k-p]]", "n", is_depth).unwrap().0);
+            assert!(!do_matching("[a-z--[a-m]&&[k-p]]", "q", is_depth).unwrap().0);
+
+            // 否定は演算の結果に対して行う
+            assert!(do_matching("[^a-z&&[a-c]]", "d", is_depth).unwrap().0);
+            assert!(!do_matching("[^a-z&&[a-c]]", "b", is_depth).unwrap().0);
+
+            // 演算子でない&や~はただの文字
+            assert!(do_matching("[&~]+", "&~", is_depth).unwrap().0);
+            assert!(do_matching("[a\\-\\-]", "-", is_depth).unwrap().0);
+
+            // 結果が空の文字クラスは何にもマッチしない
+            assert!(!do_matching("[a&&b]", "ab", is_depth).unwrap().0);
+        }
+
+        // 演算の対象がない
+        assert!(do_matching("[&&a]", "a", true).is_err());
+        assert!(do_matching("[a--]", "a", true).is_err());
+        assert!(do_matching("[a[b]", "a", true).is_err());
+    }
+
+    #[test]
+    fn test_先読み() {
+        // 肯定先読み。先読みした部分はマッチに含まない
+        let m = do_matching("\\w+(?=円)", "価格は1000円です", true)
+            .unwrap()
+            .1;
+        assert_eq!("価格は1000", m.unwrap().as_str());
+        let m = do_matching("\\d+(?=円)", "100ドルと200円", true).unwrap().1;
+        assert_eq!("200", m.unwrap().as_str());
+        assert!(!do_matching("a(?=b)", "ac", true).unwrap().0);
+
+        // 否定先読み
+        let m = do_matching("\\d+(?![\\d円])", "200円と300ドル", true)
+            .unwrap()
+            .1;
+        assert_eq!("300", m.unwrap().as_str());
+        assert!(do_matching("a(?!b)", "ab ac", true).unwrap().0);
+        assert!(!do_matching("a(?!b)", "ab", true).unwrap().0);
+
+        // 複数の条件を同時に満たす
+        let re = Regex::new("^(?=.*\\d)(?=.*[a-z])\\w{6,}$").unwrap();
+        assert!(re.is_match("abc123").unwrap());
+        assert!(!re.is_match("abcdef").unwrap());
+        assert!(!re.is_match("abc12").unwrap());
+
+        // 肯定先読みの中のキャプチャは残る
+        let re = Regex::new("(?=(\\w+))a").unwrap();
+        let caps = re.captures("abc").unwrap().unwrap();
+        assert_eq!("a", caps.get(0).unwrap().as_str());
+        assert_eq!("abc", caps.get(1).unwrap().as_str());
+
+        // 空の先読み
+        assert!(do_matching("a(?=)", "a", true).unwrap().0);
+        assert!(!do_matching("a(?!)", "a", true).unwrap().0);
+
+        // 幅優先探索では評価できない
+        let err = Regex::with_mode("a(?=b)", false).unwrap_err();
+        assert!(err.to_string().contains("lookahead"));
+        assert!(do_matching("a(?!b)", "ac", false).is_err());
+    }
+
+    #[test]
+    fn test_後読み() {
+        // 肯定後読み。$は含まずに数字だけを取り出す
+        let m = do_matching("(?<=\\$)\\d+", "100円と$250", true).unwrap().1;
+        assert_eq!("250", m.unwrap().as_str());
+        let m = do_matching("(?<=税込)\\d+円", "税抜100円 税込110円", true)
+            .unwrap()
+            .1;
+        assert_eq!("110円", m.unwrap().as_str());
+        assert!(!do_matching("(?<=a)b", "cb", true).unwrap().0);
+        // 文字列の先頭より前は読めない
+        assert!(!do_matching("(?<=a)b", "b", true).unwrap().0);
+
+        // 否定後読み
+        let m = do_matching("(?<!\\$)\\b\\d+", "$100 200", true).unwrap().1;
+        assert_eq!("200", m.unwrap().as_str());
+        assert!(do_matching("(?<!a)b", "b", true).unwrap().0);
+        assert!(!do_matching("(?<!a)b", "ab", true).unwrap().0);
+
+        // 長さが可変でも上限があれば良い
+        let re = Regex::new("(?<=(?:USD|\\$) ?)\\d+").unwrap();
+        assert_eq!("5", re.find("USD 5").unwrap().unwrap().as_str());
+        assert_eq!("7", re.find("$7").unwrap().unwrap().as_str());
+        assert!(re.find("JPY 9").unwrap().is_none());
+        assert!(do_matching("(?<=a{2,3}|b)c", "aac", true).unwrap().0);
+        assert!(!do_matching("(?<=a{2,3}|b)c", "ac", true).unwrap().0);
+
+        // 後読みの中のキャプチャ
+        let re = Regex::new("(?<=(\\w)(\\w))c").unwrap();
+        let caps = re.captures("abc").unwrap().unwrap();
+        assert_eq!("c", caps.get(0).unwrap().as_str());
+        assert_eq!("a", caps.get(1).unwrap().as_str());
+        assert_eq!("b", caps.get(2).unwrap().as_str());
+
+        // 先読みとの組み合わせ
+        let m = do_matching("(?<=<)\\w+(?=>)", "a <tag> b", true).unwrap().1;
+        assert_eq!("tag", m.unwrap().as_str());
+        assert!(do_matching("(?<=a(?=bc))b", "abc", true).unwrap().0);
+        assert!(!do_matching("(?<=a(?=bd))b", "abc", true).unwrap().0);
+
+        // 長さに上限のない後読みはエラー
+        assert!(do_matching("(?<=a*)b", "b", true).is_err());
+        assert!(do_matching("(?<!a{2,})b", "b", true).is_err());
+        assert!(do_matching("(?<=a+?)b", "b", true).is_err());
+
+        // 名前付きグループは従来どおり
+        let re = Regex::new("(?<x>a)").unwrap();
+        assert!(re.captures("a").unwrap().unwrap().name("x").is_some());
+
+        // 幅優先探索では評価できない
+        let err = Regex::with_mode("(?<=a)b", false).unwrap_err();
+        assert!(err.to_string().contains("lookbehind"));
+    }
+
+    #[test]
+    fn test_後方参照() {
+        // 繰り返された単語
+        let m = do_matching("\\b(\\w+) \\1\\b", "this is is a pen", true)
+            .unwrap()
+            .1;
+        assert_eq!("is is", m.unwrap().as_str());
         assert!(
-            !do_matching("^あいうえお$", "んあいうえおか", true)
+            !do_matching("\\b(\\w+) \\1\\b", "this is a pen", true)
                 .unwrap()
                 .0
         );
+        let m = do_matching("(.)\\1", "あいいう", true).unwrap().1;
+        assert_eq!("いい", m.unwrap().as_str());
+
+        // タグの対応
+        let re = Regex::new("<(?P<tag>\\w+)>[^<]*</\\k<tag>>").unwrap();
+        let m = re.find("<b>太字</i> <i>斜体</i>").unwrap().unwrap();
+        assert_eq!("<i>斜体</i>", m.as_str());
+        let re = Regex::new("<(\\w+)><(\\w+)>[^<]*</\\2></\\1>").unwrap();
+        assert!(re.is_match("<p><b>x</b></p>").unwrap());
+        assert!(!re.is_match("<p><b>x</p></b>").unwrap());
+
+        // キャプチャしていないグループへの参照は失敗する
+        assert!(!do_matching("(a)?b\\1", "b", true).unwrap().0);
+        assert!(do_matching("(a)?b\\1", "aba", true).unwrap().0);
+        // 空文字列をキャプチャした場合は空文字列にマッチする
+        assert!(do_matching("(a*)b\\1c", "bc", true).unwrap().0);
+
+        // 大文字小文字を区別しない
+        assert!(do_matching("(?i)(ab)\\1", "abAB", true).unwrap().0);
+        assert!(!do_matching("(ab)\\1", "abAB", true).unwrap().0);
+
+        // 先読みとの組み合わせ
+        let re = Regex::new("(\\w)(?=\\w*\\1)").unwrap();
+        assert_eq!("b", re.find("abcb").unwrap().unwrap().as_str());
+
+        // 存在しないグループや、まだ開始していないグループへの参照はエラー
+        assert!(do_matching("(a)\\2", "aa", true).is_err());
+        assert!(do_matching("\\1(a)", "aa", true).is_err());
+        assert!(do_matching("(?<x>a)\\k<y>", "aa", true).is_err());
+        assert!(do_matching("(a)\\k", "aa", true).is_err());
+        // 後方参照を含む後読みは長さに上限がない
+        assert!(do_matching("(a)(?<=\\1)", "aa", true).is_err());
+
+        // 幅優先探索では評価できない
+        let err = Regex::with_mode("(a)\\1", false).unwrap_err();
+        assert!(err.to_string().contains("backreference"));
+    }
+
+    #[test]
+    fn test_アトミックグループ() {
+        // 一度マッチしたら中の別の分岐は試さない
+        assert!(do_matching("a(?>bc|b)c", "abcc", true).unwrap().0);
+        assert!(!do_matching("a(?>bc|b)c", "abc", true).unwrap().0);
+        assert!(do_matching("a(?:bc|b)c", "abc", true).unwrap().0);
+        let m = do_matching("(?>a+)b", "aaab", true).unwrap().1;
+        assert_eq!("aaab", m.unwrap().as_str());
+
+        // 強欲な量指定子
+        assert!(!do_matching("a*+a", "aaa", true).unwrap().0);
+        assert!(!do_matching("a++a", "aaa", true).unwrap().0);
+        assert!(!do_matching("a?+a", "a", true).unwrap().0);
+        assert!(!do_matching("a{1,3}+a", "aaa", true).unwrap().0);
+        let m = do_matching("\\d++円", "価格1200円", true).unwrap().1;
+        assert_eq!("1200円", m.unwrap().as_str());
+        assert!(do_matching("\"[^\"]*+\"", "say \"hi\"", true).unwrap().0);
+        // 非貪欲な量指定子の後の+は通常の+
+        let m = do_matching("a+?+", "aaa", true).unwrap().1;
+        assert_eq!("aaa", m.unwrap().as_str());
+
+        // 中のキャプチャは残る
+        let re = Regex::new("(?>(a+))b").unwrap();
+        let caps = re.captures("aab").unwrap().unwrap();
+        assert_eq!("aa", caps.get(1).unwrap().as_str());
+
+        // 破滅的なバックトラックを防ぐ。アトミックでなければ2^30通り試すことになる
+        let haystack = "a".repeat(30) + "c";
+        assert!(!do_matching("(?>a+)+b", &haystack, true).unwrap().0);
+        assert!(!do_matching("(?:a++)+b", &haystack, true).unwrap().0);
+
+        // 後読みの中でも使える
+        assert!(do_matching("(?<=(?>ab|a)c)d", "acd", true).unwrap().0);
+
+        // 幅優先探索では評価できない
+        let err = Regex::with_mode("a++", false).unwrap_err();
+        assert!(err.to_string().contains("atomic group"));
+    }
+
+    #[test]
+    fn test_条件分岐() {
+        // 引用符で囲まれているか、囲まれていないかのどちらか
+        let re = Regex::new("^(\")?\\w+(?(1)\")$").unwrap();
+        assert!(re.is_match("\"value\"").unwrap());
+        assert!(re.is_match("value").unwrap());
+        assert!(!re.is_match("\"value").unwrap());
+        assert!(!re.is_match("value\"").unwrap());
+
+        // noの選択肢
+        let re = Regex::new("^(<)?\\w+(?(1)>|;)$").unwrap();
+        assert!(re.is_match("<tag>").unwrap());
+        assert!(re.is_match("tag;").unwrap());
+        assert!(!re.is_match("<tag;").unwrap());
+        assert!(!re.is_match("tag>").unwrap());
+
+        // 名前で条件を指定する
+        let re = Regex::new("^(?<open>\\()?\\d+(?(<open>)\\))$").unwrap();
+        assert!(re.is_match("(123)").unwrap());
+        assert!(re.is_match("123").unwrap());
+        assert!(!re.is_match("(123").unwrap());
+        let re = Regex::new("^(?<open>\\()?\\d+(?(open)\\))$").unwrap();
+        assert!(re.is_match("(123)").unwrap());
+
+        // 選択肢の中に入れ子のOrがあっても良い
+        let re = Regex::new("^(a)?(?(1)(?:b|c)|d)$").unwrap();
+        assert!(re.is_match("ac").unwrap());
+        assert!(re.is_match("d").unwrap());
+        assert!(!re.is_match("ad").unwrap());
+
+        // 不正な条件
+        assert!(do_matching("(a)(?(2)b)", "ab", true).is_err());
+        assert!(do_matching("(a)(?(0)b)", "ab", true).is_err());
+        assert!(do_matching("(a)(?(x)b)", "ab", true).is_err());
+        assert!(do_matching("(a)(?(1x)b)", "ab", true).is_err());
+        assert!(do_matching("(a)(?()b)", "ab", true).is_err());
+        assert!(do_matching("(a)(?(1", "ab", true).is_err());
+        assert!(do_matching("(a)(?(1)b|c|d)", "ab", true).is_err());
+
+        // 幅優先探索では評価できない
+        let err = Regex::with_mode("(a)?(?(1)b)", false).unwrap_err();
+        assert!(err.to_string().contains("conditional group"));
+    }
+
+    #[test]
+    fn test_再帰() {
+        // 対応の取れたカッコ
+        let re = Regex::new("^(\\((?:[^()]|(?1))*\\))$").unwrap();
+        assert!(re.is_match("()").unwrap());
+        assert!(re.is_match("(a(b)c)").unwrap());
+        assert!(re.is_match("((()())(x))").unwrap());
+        assert!(!re.is_match("(()").unwrap());
+        assert!(!re.is_match("())").unwrap());
+
+        // パターン全体の呼び出し
+        let re = Regex::new("\\[(?:[^\\[\\]]|(?R))*\\]").unwrap();
+        let m = re.find("x = [1, [2, [3]], 4];").unwrap().unwrap();
+        assert_eq!("[1, [2, [3]], 4]", m.as_str());
+
+        // 名前で呼び出す
+        let re = Regex::new("^(?<p>a(?&p)?b)$").unwrap();
+        assert!(re.is_match("aaabbb").unwrap());
+        assert!(!re.is_match("aaabb").unwrap());
+
+        // 前にあるグループを部分パターンとして使う
+        let re = Regex::new("^(\\d+)-(?1)$").unwrap();
+        assert!(re.is_match("12-345").unwrap());
+        assert!(!re.is_match("12-").unwrap());
+        // 呼び出しの中のキャプチャは残らない
+        let caps = re.captures("12-345").unwrap().unwrap();
+        assert_eq!("12", caps.get(1).unwrap().as_str());
+
+        // 呼び出しの深さの上限
+        let config = Config {
+            recursion_limit: 5,
+            ..Default::default()
+        };
+        let re = Regex::with_config("^(\\((?1)?\\))$", &config).unwrap();
+        assert!(re.is_match("((((()))))").unwrap());
+        let err = re.is_match("(((((())))))").unwrap_err();
+        assert!(err.to_string().contains("recursion limit exceeded"));
+
+        // 存在しないグループや不正な形式はエラー
+        assert!(do_matching("(a)(?2)", "a", true).is_err());
+        assert!(do_matching("(?1)(a)", "a", true).is_err());
+        assert!(do_matching("(?&x)", "a", true).is_err());
+        assert!(do_matching("(a)(?1x)", "a", true).is_err());
+        assert!(do_matching("(?Rx)", "a", true).is_err());
+        assert!(do_matching("(?1", "a", true).is_err());
+        // 呼び出しを含む後読みは長さに上限がない
+        assert!(do_matching("(a)(?<=(?1))", "a", true).is_err());
+
+        // 幅優先探索では評価できない
+        let err = Regex::with_mode("a(?R)?b", false).unwrap_err();
+        assert!(err.to_string().contains("recursion"));
+    }
+
+    #[test]
+    fn test_深さ優先探索で訪れた状態を記録する() {
+        // 訪れた(pc, sp)を二度辿らないので、a?^n a^n でも指数的な爆発をしない
+        let n = 64;
+        let expr = format!("{}{}", "a?".repeat(n), "a".repeat(n));
+        let line = "a".repeat(n);
+        assert!(do_matching(&expr, &line, true).unwrap().0);
+        assert!(!do_matching(&expr, &line[1..], true).unwrap().0);
+
+        // 空文字列にマッチする繰り返しの繰り返しも止まる
+        let m = do_matching("(a*)*", "aaab", true).unwrap().1.unwrap();
+        assert_eq!("aaa", m.as_str());
+        assert!(!do_matching("(a*)*b", &"a".repeat(n), true).unwrap().0);
+
+        // leftmost-firstの結果とキャプチャは幅優先探索と同じになる
+        // memo_budgetが0なら、ビット集合を使わずに幅優先探索で評価する
+        for memo_budget in [DEFAULT_MEMO_BUDGET, 0] {
+            let config = Config {
+                memo_budget,
+                ..Default::default()
+            };
+            for (expr, line) in [
+                ("(a|ab)(c|bcd)(d*)", "abcd"),
+                ("(a+?)(a*)", "aaa"),
+                ("x*(y|xy)z", "xxxyz"),
+                ("(?:(a)|b)+", "aba"),
+            ] {
+                let depth = Regex::with_config(expr, &config).unwrap();
+                let width = Regex::with_mode(expr, false).unwrap();
+                let caps_depth = depth.captures(line).unwrap().unwrap();
+                let caps_width = width.captures(line).unwrap().unwrap();
+                for i in 0..depth.captures_len() {
+                    assert_eq!(
+                        caps_width.get(i).map(|m| (m.start(), m.end())),
+                        caps_depth.get(i).map(|m| (m.start(), m.end()))
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_長い入力でスタックが溢れない() {
+        // 後戻りの情報はヒープ上のスタックに積むので、分岐の数だけ再帰呼び出しをしない
+        let line = format!("{}c", "ab".repeat(50_000));
+        let m = do_matching("(a|b)*c", &line, true).unwrap().1.unwrap();
+        assert_eq!(line.len(), m.end());
+
+        // 後方参照があり訪れた状態を記録できない場合も同じ
+        let line = format!("x{}x", "ab".repeat(50_000));
+        assert!(do_matching("^(x)(?:a|b)*\\1$", &line, true).unwrap().0);
+
+        // 後戻りして書き換えたスロットは元に戻る
+        let caps = Regex::new("(?:(a)|b)*c")
+            .unwrap()
+            .captures("bbac")
+            .unwrap()
+            .unwrap();
+        assert_eq!("a", caps.get(1).unwrap().as_str());
+        let caps = Regex::new("(a)?(?:(a)b|ac)")
+            .unwrap()
+            .captures("ac")
+            .unwrap()
+            .unwrap();
+        assert!(caps.get(1).is_none());
+        assert!(caps.get(2).is_none());
+
+        // スタックの上限を超えるとエラーになる
+        let config = Config {
+            stack_limit: 100,
+            ..Default::default()
+        };
+        let re = Regex::with_config("(a|b)*c", &config).unwrap();
+        assert!(re.is_match("ababc").unwrap());
+        let err = re.is_match(&"ab".repeat(100)).unwrap_err();
+        assert!(err.to_string().contains("stack limit exceeded"));
+    }
+
+    #[test]
+    fn test_空文字列にマッチする繰り返しを打ち切る() {
+        // 訪れた状態を記録できない命令列でも、文字を消費しない繰り返しで止まらなくならない
+        let m = do_matching("(?=x)|(?:a*)*?c", "ab cd 1", true)
+            .unwrap()
+            .1
+            .unwrap();
+        assert_eq!("c", m.as_str());
+        let m = do_matching("(a)\\1|(?:b*)*?c", "ab cd 1", true)
+            .unwrap()
+            .1
+            .unwrap();
+        assert_eq!("c", m.as_str());
+
+        // 空の繰り返しでスタックを使い果たさない
+        let m = do_matching("^(?=.*\\d)(?:\\w*\\s?)*$", "ab cd 1", true)
+            .unwrap()
+            .1
+            .unwrap();
+        assert_eq!("ab cd 1", m.as_str());
+        let m = do_matching("(?=\\w)(?:\\w*\\s?)*", "ab cd 1", true)
+            .unwrap()
+            .1
+            .unwrap();
+        assert_eq!("ab cd 1", m.as_str());
+
+        for is_depth in [true, false] {
+            // 文字を消費せずに終わった繰り返しでループを抜ける。その繰り返しのキャプチャは残る
+            let caps = Regex::with_mode("(a*)*b", is_depth)
+                .unwrap()
+                .captures("b")
+                .unwrap()
+                .unwrap();
+            assert_eq!("", caps.get(1).unwrap().as_str());
+            // +の1回目は空文字列にマッチしてよい
+            let caps = Regex::with_mode("(a*)+b", is_depth)
+                .unwrap()
+                .captures("b")
+                .unwrap()
+                .unwrap();
+            assert_eq!("", caps.get(1).unwrap().as_str());
+            let m = do_matching("(?:a|())*c", "aac", is_depth)
+                .unwrap()
+                .1
+                .unwrap();
+            assert_eq!("aac", m.as_str());
+            // 空ループの検出用のスロットはキャプチャに含めない
+            let re = Regex::with_mode("(a?)*", is_depth).unwrap();
+            assert_eq!(2, re.captures_len());
+            assert!(re.captures("aa").unwrap().unwrap().get(2).is_none());
+        }
+    }
+
+    #[test]
+    fn test_幅優先探索の時間が入力の長さに比例する() {
+        // 開始位置ごとに評価し直すと、マッチしない入力でO(文字数^2)かかる
+        let re = Regex::with_mode(".*foo", false).unwrap();
+        let elapsed = |n: usize| {
+            let line = "a".repeat(n);
+            (0..3)
+                .map(|_| {
+                    let now = std::time::Instant::now();
+                    assert!(!re.is_match(&line).unwrap());
+                    now.elapsed()
+                })
+                .min()
+                .unwrap()
+        };
+        let short = elapsed(5_000);
+        let long = elapsed(40_000);
+        // 入力が8倍になったとき、線形なら約8倍、2乗なら約64倍になる
+        assert!(long < short * 24, "short = {short:?}, long = {long:?}");
+
+        // 一度の走査でも、最も左にあるマッチを返す
+        for (expr, line, expected) in [
+            ("b+|ab", "xaabb", "ab"),
+            ("a|ab", "xab", "a"),
+            ("\\Aab", "xab", ""),
+            ("c$", "abc", "c"),
+        ] {
+            let m = do_matching(expr, line, false).unwrap().1;
+            assert_eq!(expected, m.map_or("", |m| m.as_str()));
+        }
+        let re = Regex::with_mode("a+", false).unwrap();
+        let all = re
+            .find_iter("aa b aaa")
+            .map(|m| m.unwrap().as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["aa", "aaa"], all);
+    }
+
+    #[test]
+    fn test_訪れた状態の記録を開始位置の間で共有する() {
+        // 開始位置ごとに記録し直すと、失敗した状態を開始位置の数だけ辿り直すことになる
+        let re = Regex::new("(a|b)*c").unwrap();
+        let elapsed = |n: usize| {
+            let line = "a".repeat(n);
+            (0..3)
+                .map(|_| {
+                    let now = std::time::Instant::now();
+                    assert!(!re.is_match(&line).unwrap());
+                    now.elapsed()
+                })
+                .min()
+                .unwrap()
+        };
+        let short = elapsed(2_000);
+        let long = elapsed(16_000);
+        // 入力が8倍になったとき、線形なら約8倍、2乗なら約64倍になる
+        assert!(long < short * 24, "short = {short:?}, long = {long:?}");
+
+        // 後の開始位置からのマッチも正しく見つかる
+        let m = do_matching("(a|b)*c", "aaxabc", true).unwrap().1.unwrap();
+        assert_eq!("abc", m.as_str());
+        let re = Regex::new("a+b").unwrap();
+        let all = re
+            .find_iter("aab xab ab")
+            .map(|m| m.unwrap().as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["aab", "ab", "ab"], all);
+    }
+
+    #[test]
+    fn test_空の選択肢() {
+        for is_depth in [true, false] {
+            // |の後が空なら、空文字列にマッチする選択肢になる
+            let caps = Regex::with_mode("x(c|)y", is_depth)
+                .unwrap()
+                .captures("xy")
+                .unwrap()
+                .unwrap();
+            assert_eq!("", caps.get(1).unwrap().as_str());
+            assert!(do_matching("x(c|)y", "xcy", is_depth).unwrap().0);
+            assert!(!do_matching("x(c)y", "xy", is_depth).unwrap().0);
+            let m = do_matching("b|", "ab", is_depth).unwrap().1.unwrap();
+            assert_eq!((0, 0), (m.start(), m.end()));
+        }
+
+        // 条件分岐のnoが空でも、yesだけの条件分岐とは区別する
+        let re = Regex::new("^(x)?(?(1)a|)b$").unwrap();
+        assert!(re.is_match("xab").unwrap());
+        assert!(re.is_match("b").unwrap());
+        assert!(!re.is_match("xb").unwrap());
+        assert!(!re.is_match("ab").unwrap());
+        let m = do_matching("(a)?(?(1)a|)", "b", true).unwrap().1.unwrap();
+        assert_eq!((0, 0), (m.start(), m.end()));
+    }
+
+    #[test]
+    fn test_空の非キャプチャグループ() {
+        for is_depth in [true, false] {
+            // (?:)+は直前のbではなく空のグループを繰り返す
+            let m = do_matching("ab(?:)+", "abbb", is_depth).unwrap().1.unwrap();
+            assert_eq!("ab", m.as_str());
+            let m = do_matching("a(?:)*b", "ab", is_depth).unwrap().1.unwrap();
+            assert_eq!("ab", m.as_str());
+            let m = do_matching("ab(?:){2}", "abb", is_depth)
+                .unwrap()
+                .1
+                .unwrap();
+            assert_eq!("ab", m.as_str());
+        }
+    }
+
+    #[test]
+    fn test_フラグの変更の後の量指定子() {
+        // (?i)は量指定子の対象にならないので、直前のaを繰り返したりせずエラーにする
+        for expr in [
+            "a(?i)*", "a(?i)+", "a(?i)?", "a(?i){2}", "a(?-i)*?", "(?i)*",
+        ] {
+            assert!(Regex::new(expr).is_err(), "{expr}");
+        }
+        // フラグの変更の後に式があれば、その式を繰り返す
+        let m = do_matching("a(?i)b*", "aBBb", true).unwrap().1.unwrap();
+        assert_eq!("aBBb", m.as_str());
+        let m = do_matching("a(?i:b)*", "aBb", true).unwrap().1.unwrap();
+        assert_eq!("aBb", m.as_str());
+    }
+
+    #[test]
+    fn test_大文字小文字を区別しない文字クラスの否定() {
+        for is_depth in [true, false] {
+            // 入れ子の[^...]や\P{...}は、畳み込んでから否定する
+            for (expr, line, expected) in [
+                ("(?i)[[^a]]", "a", false),
+                ("(?i)[[^a]]", "A", false),
+                ("(?i)[[^a]]", "b", true),
+                ("(?i)[x[^a]]", "A", false),
+                ("(?i)[\\P{Ll}]", "a", false),
+                ("(?i)[\\P{Ll}]", "A", false),
+                ("(?i)[\\P{Ll}]", "1", true),
+                ("(?i)[[:^lower:]]", "a", false),
+                ("(?i)[[:^lower:]]", "B", false),
+                ("(?i)[a-z--a]", "A", false),
+                ("(?i)[a-z--a]", "B", true),
+                ("(?i)[[a-z]&&[^b]]", "B", false),
+                ("[[^a]]", "A", true),
+            ] {
+                assert_eq!(
+                    expected,
+                    do_matching(expr, line, is_depth).unwrap().0,
+                    "{expr} {line}"
+                );
+            }
+
+            // 文字クラスの外の否定と同じ結果になる
+            for c in ["a", "A", "1", "k", "K", "\u{212A}"] {
+                assert_eq!(
+                    do_matching("(?i)\\P{Ll}", c, is_depth).unwrap().0,
+                    do_matching("(?i)[\\P{Ll}]", c, is_depth).unwrap().0,
+                    "{c}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_後読みや0回の繰り返しの中にしかないグループの呼び出し() {
+        // {0}の中のグループは、定義だけして呼び出しで使える
+        let m = do_matching("(a){0}b(?1)", "xbab", true).unwrap().1.unwrap();
+        assert_eq!("ba", m.as_str());
+        let re = Regex::new("^(?:(\\d+)){0}(?1)-(?1)$").unwrap();
+        assert!(re.is_match("12-345").unwrap());
+        assert!(!re.is_match("12-").unwrap());
+        // 呼び出したグループの中からさらに呼び出す
+        let m = do_matching("((a)){0}(?2)(?1)", "aa", true)
+            .unwrap()
+            .1
+            .unwrap();
+        assert_eq!("aa", m.as_str());
+
+        // 後読みの中のグループは、前向きに呼び出す
+        let m = do_matching("(?<=(a))b(?1)", "abab", true)
+            .unwrap()
+            .1
+            .unwrap();
+        assert_eq!("ba", m.as_str());
+        let m = do_matching("(?<=(ab))c(?1)", "abcab", true)
+            .unwrap()
+            .1
+            .unwrap();
+        assert_eq!("cab", m.as_str());
+    }
+
+    #[test]
+    fn test_部分プログラムの入れ子の深さの上限() {
+        // lookaroundやアトミックグループの入れ子も、呼び出しと同じく深さに数える
+        for (expr, line) in [
+            ("(?>(?>(?>(?>(?R)))))", "a".to_string()),
+            ("(?=(?=(?=(?R))))", "a".to_string()),
+            ("(?R)", "a".to_string()),
+            ("a(?R)?b", "a".repeat(900) + &"b".repeat(900)),
+            ("(\\((?:[^()]|(?1))*\\))", "(".repeat(400)),
+        ] {
+            let re = Regex::new(expr).unwrap();
+            let err = re.is_match(&line).unwrap_err();
+            assert!(
+                err.to_string().contains("recursion limit exceeded"),
+                "{expr}"
+            );
+        }
+
+        // 上限の範囲に収まる入れ子はマッチする
+        let re = Regex::new("^(a(?1)?b)$").unwrap();
+        let line = "a".repeat(50) + &"b".repeat(50);
+        assert!(re.is_match(&line).unwrap());
+        let config = Config {
+            recursion_limit: 3,
+            ..Default::default()
+        };
+        let re = Regex::with_config("(?=(?=(?=a)))", &config).unwrap();
+        assert!(re.is_match("a").unwrap());
+        let re = Regex::with_config("(?=(?=(?=(?=a))))", &config).unwrap();
+        assert!(re.is_match("a").is_err());
+    }
+
+    #[test]
+    fn test_小さいスタックでも深い入れ子でクラッシュしない() {
+        // 2MBのスタックのスレッドで評価しても、スタックが溢れずにエラーを返す
+        let handle = std::thread::Builder::new()
+            .stack_size(2 * 1024 * 1024)
+            .spawn(|| {
+                let lines = [
+                    ("(?>(?>(?>(?>(?R)))))", "a".to_string()),
+                    ("(?=(?=(?=(?R))))", "a".to_string()),
+                    ("(?R)", "a".to_string()),
+                    ("a(?R)?b", "a".repeat(900) + &"b".repeat(900)),
+                    ("(\\((?:[^()]|(?1))*\\))", "(".repeat(400)),
+                ];
+                lines
+                    .iter()
+                    .map(|(expr, line)| {
+                        let re = Regex::new(expr).unwrap();
+                        re.is_match(line).map_err(|e| e.to_string())
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .unwrap();
+        for result in handle.join().unwrap() {
+            assert!(result.unwrap_err().contains("recursion limit exceeded"));
+        }
+    }
+
+    #[test]
+    fn test_空の繰り返しでループを抜ける結果は評価方法によらない() {
+        // 記録ありの深さ優先探索、記録なしの深さ優先探索(lookaroundがあると記録しない)、
+        // 幅優先探索(memo_budgetが0のときも)で結果が同じになる
+        for (expr, line, expected) in [
+            ("(?:b?a??)*", "bbabb", "bb"),
+            ("(?:.*?)+", "ab", ""),
+            ("(?:(?:b?)+|a?)*", "baa", "b"),
+            ("(?:(a?|b?)+)+?", "aaabb", "aaa"),
+            ("((?:a*?)*)b", "aab", "aab"),
+        ] {
+            for suffix in ["", "(?=)", "(?!x)"] {
+                let expr = format!("{expr}{suffix}");
+                let memo = Regex::new(&expr).unwrap();
+                let fallback = Regex::with_config(
+                    &expr,
+                    &Config {
+                        memo_budget: 0,
+                        ..Default::default()
+                    },
+                )
+                .unwrap();
+                let width = Regex::with_mode(&expr, false);
+                for re in [Ok(memo), Ok(fallback), width] {
+                    // lookaroundは幅優先探索では評価できない
+                    let Ok(re) = re else { continue };
+                    let caps = re.captures(line).unwrap().unwrap();
+                    assert_eq!(expected, caps.get(0).unwrap().as_str(), "{expr}");
+                }
+            }
+        }
+
+        // キャプチャも同じになる
+        for expr in ["(?:(b?))*", "((?:(b?))*)((?:a+)*)", "(?:(a|())*c)"] {
+            let caps = |re: Regex| {
+                let caps = re.captures("bbac").unwrap().unwrap();
+                (0..re.captures_len())
+                    .map(|i| caps.get(i).map(|m| (m.start(), m.end())))
+                    .collect::<Vec<_>>()
+            };
+            let memo = caps(Regex::new(expr).unwrap());
+            let no_memo = caps(Regex::new(&format!("{expr}(?=)")).unwrap());
+            let width = caps(Regex::with_mode(expr, false).unwrap());
+            assert_eq!(memo, no_memo, "{expr}");
+            assert_eq!(memo, width, "{expr}");
+        }
     }
 }